@@ -1,4 +1,9 @@
 fn main() {
+    // Host builds are only for tests, they link against std. The variable is not set when
+    // the linker runs this as its error handling script
+    if std::env::var("CARGO_CFG_TARGET_ARCH").is_ok_and(|arch| arch != "riscv32") {
+        return;
+    }
     linker_be_nice();
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
     println!("cargo:rustc-link-arg=-Tlinkall.x");
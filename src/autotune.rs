@@ -0,0 +1,160 @@
+use embassy_time::Instant;
+
+use crate::driver::Side;
+
+// Fixed-point scale of the gains, same as in pid
+const SCALE: i32 = 256;
+// Oscillation before this many periods is the start-up transient
+const SKIP_PERIODS: u8 = 2;
+const MEASURE_PERIODS: u8 = 4;
+
+// Result of a relay experiment
+#[derive(Debug, Copy, Clone)]
+pub struct UltimateGain {
+    // Ultimate gain, duty per pulses/s scaled by SCALE
+    pub ku: i32,
+    // Ultimate period
+    pub tu: u32, // ms
+}
+
+impl UltimateGain {
+    // Ziegler-Nichols PI gains for a controller updated every `dt` ms.
+    // Encoder speed is too coarse for the derivative, so kd is left at 0
+    pub fn gains(&self, dt: u32) -> (i32, i32, i32) {
+        let kp = self.ku * 45 / 100;
+        // Ti = Tu / 1.2, per update Ki = Kp * dt / Ti
+        let ki = kp * 12 * dt as i32 / (10 * self.tu.max(1) as i32);
+        (kp, ki, 0)
+    }
+}
+
+// Relay (Astrom-Hagglund) experiment: the output switches between +amplitude and
+// -amplitude around the operating point whenever the speed crosses the target, which
+// makes the wheel speed oscillate at the ultimate period of the loop
+pub struct Relay {
+    target: i32,
+    amplitude: i32,
+    // Crossing band, so encoder noise doesn't make the relay chatter
+    hysteresis: i32,
+    high: bool,
+    last_rise: Option<u64>,
+    periods: u8,
+    period_sum: u64,
+    max: i32,
+    min: i32,
+    swing_sum: i32,
+}
+
+impl Relay {
+    pub fn new(target: i32, amplitude: i32, hysteresis: i32) -> Self {
+        Self {
+            target,
+            amplitude,
+            hysteresis,
+            high: true,
+            last_rise: None,
+            periods: 0,
+            period_sum: 0,
+            max: i32::MIN,
+            min: i32::MAX,
+            swing_sum: 0,
+        }
+    }
+
+    // Feeds a speed measured at `time` ms. Returns the output offset to apply
+    pub fn update(&mut self, time: u64, speed: i32) -> i32 {
+        self.max = self.max.max(speed);
+        self.min = self.min.min(speed);
+
+        if self.high && speed > self.target + self.hysteresis {
+            self.high = false;
+        } else if !self.high && speed < self.target - self.hysteresis {
+            self.high = true;
+            // A period is from one switch to high to the next one
+            if let Some(last_rise) = self.last_rise {
+                self.periods += 1;
+                if self.periods > SKIP_PERIODS {
+                    self.period_sum += time - last_rise;
+                    self.swing_sum += self.max - self.min;
+                }
+            }
+            self.last_rise = Some(time);
+            self.max = i32::MIN;
+            self.min = i32::MAX;
+        }
+
+        if self.high {
+            self.amplitude
+        } else {
+            -self.amplitude
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.periods >= SKIP_PERIODS + MEASURE_PERIODS
+    }
+
+    // Ku = 4d / (pi a), where d is the relay amplitude and a is the oscillation amplitude.
+    // pi is approximated as 355/113
+    pub fn result(&self) -> Option<UltimateGain> {
+        if !self.done() {
+            return None;
+        }
+        let measured = (self.periods - SKIP_PERIODS) as i32;
+        let swing = self.swing_sum / measured;
+        if swing <= 0 {
+            return None;
+        }
+        Some(UltimateGain {
+            // swing is peak to peak, twice the oscillation amplitude
+            ku: (8 * self.amplitude * SCALE * 113 / (355 * swing)).max(1),
+            tu: (self.period_sum / measured as u64) as u32,
+        })
+    }
+}
+
+// Auto-tune of both wheels, one after another. MotorsSm drives the wheel
+pub struct AutoTune {
+    pub side: Side,
+    pub relay: Relay,
+    pub results: [Option<UltimateGain>; 2],
+    pub start: Instant,
+    time: Instant,
+    pulses: u32,
+    // Low-pass filtered wheel speed, pulses/s
+    speed: i32,
+}
+
+impl AutoTune {
+    pub fn new(side: Side, relay: Relay, pulses: u32) -> Self {
+        Self {
+            side,
+            relay,
+            results: [None, None],
+            start: Instant::now(),
+            time: Instant::now(),
+            pulses,
+            speed: 0,
+        }
+    }
+
+    // Moves on to the other wheel, keeping the results
+    pub fn restart(&mut self, side: Side, relay: Relay, pulses: u32) {
+        self.side = side;
+        self.relay = relay;
+        self.start = Instant::now();
+        self.time = Instant::now();
+        self.pulses = pulses;
+        self.speed = 0;
+    }
+
+    // Speed since the previous measurement, filtered
+    pub fn measure(&mut self, pulses: u32) -> i32 {
+        let dt = self.time.elapsed().as_millis().max(1) as u32;
+        let raw = ((pulses - self.pulses) * 1000 / dt) as i32;
+        self.time = Instant::now();
+        self.pulses = pulses;
+        self.speed = (3 * self.speed + raw) / 4;
+        self.speed
+    }
+}
@@ -16,15 +16,27 @@ esp_bootloader_esp_idf::esp_app_desc!();
 async fn recv_task(mut receiver: EspNowReceiver<'static>) {
     loop {
         let r = receiver.receive_async().await;
+        if let Some(pkt) = telemetry::unpack_program(r.data()) {
+            log::info!("program={:?}", pkt.colors());
+            continue;
+        }
         match telemetry::unpack(r.data()) {
             Some(pkt) => {
                 log::info!(
-                    "battery={}mV left_duty={} right_duty={} left_pulses={} right_pulses={}",
+                    "battery={}mV left_duty={} right_duty={} left_pulses={} right_pulses={} compensation={} kp={} ki={} kd={} slips={} drags={} surface={} faulted={}",
                     pkt.battery_mv,
                     pkt.left_duty,
                     pkt.right_duty,
                     pkt.left_pulses,
                     pkt.right_pulses,
+                    pkt.compensation,
+                    pkt.kp,
+                    pkt.ki,
+                    pkt.kd,
+                    pkt.slips,
+                    pkt.drags,
+                    pkt.surface,
+                    pkt.faulted,
                 );
             }
             None => {
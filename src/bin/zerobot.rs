@@ -6,14 +6,15 @@ use embassy_time::{Duration, Instant, Timer};
 use esp_backtrace as _;
 use esp_hal::{
     analog::adc,
-    gpio::{DriveMode, Pin},
+    gpio::Pin,
     i2c,
-    ledc::{self, channel::ChannelIFace, timer::TimerIFace},
+    ledc::{self, timer::TimerIFace},
     peripherals, spi,
     time::Rate,
     timer::timg::TimerGroup,
 };
-use esp_radio::esp_now::{BROADCAST_ADDRESS, EspNowSender};
+use esp_radio::esp_now::{BROADCAST_ADDRESS, EspNowReceiver, EspNowSender};
+use esp_storage::FlashStorage;
 
 use smart_leds::{RGB, SmartLedsWrite};
 use ws2812_spi::Ws2812;
@@ -24,23 +25,53 @@ use esp_zerobot_nostd::color::color_task;
 use esp_zerobot_nostd::comm::{SENSOR_CHANNEL, SensorMessage, TELEMETRY_CHANNEL};
 use esp_zerobot_nostd::control::ControlSm;
 use esp_zerobot_nostd::distance::distance_task;
-use esp_zerobot_nostd::motors::{Motors, MotorsSm};
-use esp_zerobot_nostd::telemetry;
-use esp_zerobot_nostd::{encoder, motors};
+use esp_zerobot_nostd::driver::MotorDriver;
+use esp_zerobot_nostd::encoder;
+use esp_zerobot_nostd::motors::{self, Motors, MotorsSm};
+use esp_zerobot_nostd::program::Tape;
+use esp_zerobot_nostd::remote::{self, RemoteCommand};
+use esp_zerobot_nostd::storage::Store;
+use esp_zerobot_nostd::surface::Surface;
+use esp_zerobot_nostd::telemetry::{self, Telemetry};
 
 use esp_alloc as _;
 
+#[path = "zerobot/board.rs"]
+mod board;
+
+// Learned config is saved at most this often, so moves don't wear out the flash
+const CONFIG_SAVE_INTERVAL: u64 = 60_000; // ms
+
 #[embassy_executor::task]
 async fn telemetry_task(mut sender: EspNowSender<'static>) {
     loop {
-        let pkt = TELEMETRY_CHANNEL.receive().await;
-        let buf = telemetry::pack(&pkt);
-        if let Err(e) = sender.send_async(&BROADCAST_ADDRESS, &buf).await {
+        let res = match TELEMETRY_CHANNEL.receive().await {
+            Telemetry::Status(pkt) => {
+                let buf = telemetry::pack(&pkt);
+                sender.send_async(&BROADCAST_ADDRESS, &buf).await
+            }
+            Telemetry::Program(pkt) => {
+                let buf = telemetry::pack_program(&pkt);
+                sender.send_async(&BROADCAST_ADDRESS, &buf).await
+            }
+        };
+        if let Err(e) = res {
             log::warn!("ESP-NOW send error: {:?}", e);
         }
     }
 }
 
+#[embassy_executor::task]
+async fn remote_task(mut receiver: EspNowReceiver<'static>) {
+    loop {
+        let r = receiver.receive_async().await;
+        match remote::unpack(r.data()) {
+            Some(cmd) => SENSOR_CHANNEL.send(SensorMessage::Remote(cmd)).await,
+            None => log::debug!("Ignoring packet ({} bytes)", r.data().len()),
+        }
+    }
+}
+
 #[embassy_executor::task]
 async fn battery_task(adc: peripherals::ADC1<'static>, pin: peripherals::GPIO4<'static>) {
     log::info!("Starting battery task");
@@ -60,6 +91,47 @@ async fn battery_task(adc: peripherals::ADC1<'static>, pin: peripherals::GPIO4<'
     }
 }
 
+// Saved config of a surface, or its defaults
+fn load_config(store: Option<&mut Store>, surface: Surface) -> motors::Config {
+    match store.map(|store| motors::Config::load(store, surface)) {
+        Some(Ok(config)) => {
+            log::info!("Loaded {} motor config: {:?}", surface.name(), config);
+            config
+        }
+        res => {
+            log::info!(
+                "Using default {} motor config ({:?})",
+                surface.name(),
+                res.map(|r| r.err())
+            );
+            motors::Config::for_surface(surface)
+        }
+    }
+}
+
+fn save_config<D: MotorDriver>(store: &mut Store, motors_sm: &mut MotorsSm<D>, surface: Surface) {
+    match motors_sm.config().save(store, surface) {
+        Ok(()) => {
+            log::info!("{} motor config saved", surface.name());
+            motors_sm.config_saved();
+        }
+        Err(e) => log::warn!("Failed to save motor config: {:?}", e),
+    }
+}
+
+fn dispatch<D: MotorDriver>(
+    control_sm: &mut ControlSm,
+    motors_sm: &mut MotorsSm<D>,
+    msg: SensorMessage,
+) {
+    if let Some(cmd) = control_sm.process_event(msg)
+        && let Err(x) = motors_sm.process_cmd(cmd)
+    {
+        log::info!("motors_sm.process_cmd returned {:?}", x);
+        control_sm.command_failed();
+    }
+}
+
 esp_bootloader_esp_idf::esp_app_desc!();
 
 #[esp_rtos::main]
@@ -127,46 +199,32 @@ async fn main(spawner: Spawner) -> ! {
         })
         .unwrap();
 
-    let mut mot1_1 = ledc.channel(ledc::channel::Number::Channel0, peripherals.GPIO3);
-    mot1_1
-        .configure(ledc::channel::config::Config {
-            timer: &lstimer0,
-            duty_pct: 0,
-            drive_mode: DriveMode::PushPull,
-        })
-        .unwrap();
-
-    let mut mot1_2 = ledc.channel(ledc::channel::Number::Channel1, peripherals.GPIO2);
-    mot1_2
-        .configure(ledc::channel::config::Config {
-            timer: &lstimer0,
-            duty_pct: 0,
-            drive_mode: DriveMode::PushPull,
-        })
-        .unwrap();
-    let mut mot2_1 = ledc.channel(ledc::channel::Number::Channel2, peripherals.GPIO0);
-    mot2_1
-        .configure(ledc::channel::config::Config {
-            timer: &lstimer1,
-            duty_pct: 0,
-            drive_mode: DriveMode::PushPull,
-        })
-        .unwrap();
-    let mut mot2_2 = ledc.channel(ledc::channel::Number::Channel3, peripherals.GPIO1);
-    mot2_2
-        .configure(ledc::channel::config::Config {
-            timer: &lstimer1,
-            duty_pct: 0,
-            drive_mode: DriveMode::PushPull,
-        })
-        .unwrap();
+    let driver = board::motor_driver!(peripherals, ledc, lstimer0, lstimer1).unwrap();
 
     let trigger = peripherals.GPIO7.degrade();
     let echo = peripherals.GPIO6.degrade();
 
-    let motors = Motors::init(mot1_1, mot1_2, mot2_1, mot2_2, motors::Config::default());
+    let mut store = Store::new(FlashStorage::new(peripherals.FLASH))
+        .inspect_err(|e| log::warn!("No config storage: {:?}", e))
+        .ok();
+    let mut surface = store
+        .as_mut()
+        .and_then(|store| Surface::load(store).ok())
+        .unwrap_or(Surface::Table);
+    let config = load_config(store.as_mut(), surface);
+    let mut config_saved = Instant::now();
+    // Surface to switch to once the motors are stopped
+    let mut new_surface: Option<Surface> = None;
+
+    let motors = Motors::init(driver, config);
     let mut motors_sm = MotorsSm::init(motors);
     let mut control_sm = ControlSm::init();
+    if let Some(tape) = store.as_mut().and_then(|store| Tape::load(store).ok()) {
+        log::info!("Loaded a program of {} mats", tape.len());
+        control_sm.set_tape(tape);
+    }
+    // Program is saved once the motors are stopped
+    let mut save_program = false;
 
     let battery_pin = peripherals.GPIO4;
     let adc = peripherals.ADC1;
@@ -178,8 +236,9 @@ async fn main(spawner: Spawner) -> ! {
     let (_wifi_ctrl, interfaces) =
         esp_radio::wifi::new(peripherals.WIFI, Default::default()).unwrap();
     let esp_now = interfaces.esp_now;
-    let (_manager, sender, _receiver) = esp_now.split();
+    let (_manager, sender, receiver) = esp_now.split();
     spawner.spawn(telemetry_task(sender).unwrap());
+    spawner.spawn(remote_task(receiver).unwrap());
 
     led.write([RGB::new(0, 0, 0)]).unwrap();
     log::info!("Starting main loop");
@@ -201,39 +260,58 @@ async fn main(spawner: Spawner) -> ! {
 
         if let Either::Second(msg) = res {
             if let SensorMessage::Voltage(v) = msg {
+                motors_sm.set_battery_voltage(v);
                 let (left_duty, right_duty) = motors_sm.current_duties();
                 let (left_pulses, right_pulses) = motors_sm.last_pulse_counts();
+                let (kp, ki, kd) = motors_sm.pid_gains();
+                let (slips, drags) = motors_sm.traction_events();
                 let pkt = telemetry::TelemetryPacket {
                     battery_mv: v,
                     left_duty,
                     right_duty,
                     left_pulses,
                     right_pulses,
+                    compensation: motors_sm.compensation(),
+                    kp: kp.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+                    ki: ki.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+                    kd: kd.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+                    slips,
+                    drags,
+                    surface: surface.to_u8(),
+                    faulted: motors_sm.fault().is_some(),
                 };
-                TELEMETRY_CHANNEL.try_send(pkt).ok();
+                TELEMETRY_CHANNEL.try_send(Telemetry::Status(pkt)).ok();
             }
 
-            let mut is_color = false;
             if let SensorMessage::Color(color) = msg {
                 led.write([color.to_rgb()]).unwrap();
-                is_color = true;
             }
 
-            // Supress color messages when motor_sm is busy
-            // Otherwise it may clear "last_turn" flag, but motors_sm will reject
-            // the cmd, since it's busy
-            //
-            // Other events should be passed, since it may result in emergency stop
-            if !motors_sm.busy() || !is_color {
-                let cmd = control_sm.process_event(msg);
-                if let Some(cmd) = cmd {
-                    match motors_sm.process_cmd(cmd) {
-                        Ok(()) => {}
-                        Err(x) => {
-                            log::info!("motors_sm.process_cmd returned {:?}", x);
-                        }
-                    }
-                }
+            if let SensorMessage::Remote(RemoteCommand::SelectSurface(s)) = msg {
+                new_surface = Some(s);
+            }
+
+            if let SensorMessage::Remote(RemoteCommand::SaveProgram) = msg {
+                save_program = true;
+            }
+
+            if let SensorMessage::Remote(RemoteCommand::ClearFault) = msg
+                && let Err(e) = motors_sm.clear_fault()
+            {
+                log::warn!("Motor driver is still failing: {:?}", e);
+            }
+
+            dispatch(&mut control_sm, &mut motors_sm, msg);
+            if let Some(s) = control_sm.take_surface() {
+                new_surface = Some(s);
+            }
+
+            // Program is sent when asked for and whenever it changes, so an editor stays in sync
+            if control_sm.take_program_changed()
+                || matches!(msg, SensorMessage::Remote(RemoteCommand::ViewProgram))
+            {
+                let pkt = telemetry::ProgramPacket::new(control_sm.tape());
+                TELEMETRY_CHANNEL.try_send(Telemetry::Program(pkt)).ok();
             }
         }
 
@@ -244,6 +322,56 @@ async fn main(spawner: Spawner) -> ! {
             } else {
                 now = None;
             }
+
+            if let Some(event) = motors_sm.take_event() {
+                dispatch(
+                    &mut control_sm,
+                    &mut motors_sm,
+                    SensorMessage::Motion(event),
+                );
+            }
+
+            if let Some(s) = new_surface
+                && !motors_sm.busy()
+            {
+                new_surface = None;
+                if s != surface {
+                    log::info!("Switching to {} motor config", s.name());
+                    // What was learned on the previous surface is kept for it
+                    if let Some(store) = store.as_mut() {
+                        if motors_sm.config_dirty() {
+                            save_config(store, &mut motors_sm, surface);
+                        }
+                        if let Err(e) = s.save(store) {
+                            log::warn!("Failed to save surface: {:?}", e);
+                        }
+                    }
+                    let config = load_config(store.as_mut(), s);
+                    if let Err(e) = motors_sm.set_config(config) {
+                        log::warn!("Failed to switch motor config: {:?}", e);
+                    } else {
+                        surface = s;
+                    }
+                }
+            }
+
+            if save_program && !motors_sm.busy() {
+                save_program = false;
+                match store.as_mut().map(|store| control_sm.tape().save(store)) {
+                    Some(Ok(())) => log::info!("Program saved"),
+                    res => log::warn!("Failed to save program: {:?}", res.map(|r| r.err())),
+                }
+            }
+
+            // Flash writes stall interrupts, so only save while the motors are stopped
+            if let Some(store) = store.as_mut()
+                && motors_sm.config_dirty()
+                && !motors_sm.busy()
+                && config_saved.elapsed().as_millis() >= CONFIG_SAVE_INTERVAL
+            {
+                config_saved = Instant::now();
+                save_config(store, &mut motors_sm, surface);
+            }
         }
     }
 }
@@ -0,0 +1,95 @@
+// Motor driver wiring.
+// LM298N-like driver is used unless one of the driver-* features is enabled. If several are,
+// the first one of driver-drv8833, driver-tb6612 and driver-pwm-dir wins.
+
+use esp_hal::gpio::{DriveMode, Level, Output, OutputConfig, OutputPin, interconnect};
+use esp_hal::ledc::{self, channel::ChannelIFace, timer::TimerIFace};
+
+pub fn pwm<'a>(
+    ledc: &ledc::Ledc<'a>,
+    number: ledc::channel::Number,
+    pin: impl interconnect::PeripheralOutput<'a>,
+    timer: &'a dyn TimerIFace<ledc::LowSpeed>,
+) -> ledc::channel::Channel<'a, ledc::LowSpeed> {
+    let mut channel = ledc.channel(number, pin);
+    channel
+        .configure(ledc::channel::config::Config {
+            timer,
+            duty_pct: 0,
+            drive_mode: DriveMode::PushPull,
+        })
+        .unwrap();
+    channel
+}
+
+// Only drivers with separate direction inputs need plain outputs
+#[allow(dead_code)]
+pub fn output<'a>(pin: impl OutputPin + 'a) -> Output<'a> {
+    Output::new(pin, Level::Low, OutputConfig::default())
+}
+
+// IN1/IN2 of each motor
+#[cfg(not(any(
+    feature = "driver-drv8833",
+    feature = "driver-tb6612",
+    feature = "driver-pwm-dir"
+)))]
+macro_rules! motor_driver {
+    ($p:ident, $ledc:ident, $timer0:ident, $timer1:ident) => {
+        esp_zerobot_nostd::driver::LedcHBridge::new(
+            board::pwm(&$ledc, ledc::channel::Number::Channel0, $p.GPIO3, &$timer0),
+            board::pwm(&$ledc, ledc::channel::Number::Channel1, $p.GPIO2, &$timer0),
+            board::pwm(&$ledc, ledc::channel::Number::Channel2, $p.GPIO0, &$timer1),
+            board::pwm(&$ledc, ledc::channel::Number::Channel3, $p.GPIO1, &$timer1),
+        )
+    };
+}
+
+// IN1/IN2 of each motor, nSLEEP pulled up on the board
+#[cfg(feature = "driver-drv8833")]
+macro_rules! motor_driver {
+    ($p:ident, $ledc:ident, $timer0:ident, $timer1:ident) => {
+        esp_zerobot_nostd::driver::Drv8833::new(
+            board::pwm(&$ledc, ledc::channel::Number::Channel0, $p.GPIO3, &$timer0),
+            board::pwm(&$ledc, ledc::channel::Number::Channel1, $p.GPIO2, &$timer0),
+            board::pwm(&$ledc, ledc::channel::Number::Channel2, $p.GPIO0, &$timer1),
+            board::pwm(&$ledc, ledc::channel::Number::Channel3, $p.GPIO1, &$timer1),
+        )
+    };
+}
+
+// PWMx, xIN1, xIN2 of each motor, STBY pulled up on the board like nSLEEP of DRV8833.
+// There are not enough free pins on esp32-c3-zero, so the USB pins are used and logs have to be
+// read from UART, which keeps GPIO21 for its TX
+#[cfg(all(feature = "driver-tb6612", not(feature = "driver-drv8833")))]
+macro_rules! motor_driver {
+    ($p:ident, $ledc:ident, $timer0:ident, $timer1:ident) => {
+        esp_zerobot_nostd::driver::Tb6612::new(
+            board::pwm(&$ledc, ledc::channel::Number::Channel0, $p.GPIO3, &$timer0),
+            board::output($p.GPIO2),
+            board::output($p.GPIO18),
+            board::pwm(&$ledc, ledc::channel::Number::Channel1, $p.GPIO0, &$timer1),
+            board::output($p.GPIO1),
+            board::output($p.GPIO19),
+            None,
+        )
+    };
+}
+
+// PWM and direction of each motor
+#[cfg(all(
+    feature = "driver-pwm-dir",
+    not(any(feature = "driver-drv8833", feature = "driver-tb6612"))
+))]
+macro_rules! motor_driver {
+    ($p:ident, $ledc:ident, $timer0:ident, $timer1:ident) => {
+        esp_zerobot_nostd::driver::PwmDir::new(
+            board::pwm(&$ledc, ledc::channel::Number::Channel0, $p.GPIO3, &$timer0),
+            board::output($p.GPIO2),
+            board::pwm(&$ledc, ledc::channel::Number::Channel1, $p.GPIO0, &$timer1),
+            board::output($p.GPIO1),
+        )
+    };
+}
+
+pub(crate) use motor_driver;
@@ -0,0 +1,169 @@
+use crate::driver::{Direction, Side};
+use crate::storage::{Reader, Writer};
+
+// Duty is swept from 0 to 100 in CURVE_STEP steps
+pub const CURVE_POINTS: usize = 11;
+const CURVE_STEP: u8 = 10; // %
+
+// Order the sweep goes through the wheels. Each wheel goes back after going forward,
+// so the robot ends up roughly where it started
+const SWEEP_ORDER: [(Side, Direction); 4] = [
+    (Side::Left, Direction::Forward),
+    (Side::Left, Direction::Backwards),
+    (Side::Right, Direction::Forward),
+    (Side::Right, Direction::Backwards),
+];
+
+// Measured duty -> speed curve of a wheel in one direction
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SpeedCurve {
+    // Lowest duty that starts the wheel from rest
+    pub dead_band: u8,
+    // Wheel speed at duty i * CURVE_STEP, pulses/s. Never decreasing
+    pub speeds: [u16; CURVE_POINTS],
+}
+
+impl SpeedCurve {
+    // Duty for a wheel speed, interpolated between the measured points.
+    // Never below the dead band, 100 if the speed is out of reach
+    pub fn duty(&self, speed: u32) -> u8 {
+        if speed == 0 {
+            return 0;
+        }
+        for i in 1..CURVE_POINTS {
+            let (low, high) = (self.speeds[i - 1] as u32, self.speeds[i] as u32);
+            if high >= speed && high > low {
+                let duty = (i as u32 - 1) * CURVE_STEP as u32
+                    + CURVE_STEP as u32 * (speed.max(low) - low) / (high - low);
+                return (duty as u8).max(self.dead_band);
+            }
+        }
+        100
+    }
+
+    // Wheel speed expected at a duty, interpolated between the measured points
+    pub fn speed(&self, duty: u8) -> u32 {
+        if duty < self.dead_band {
+            return 0;
+        }
+        let i = (duty / CURVE_STEP) as usize;
+        if i + 1 >= CURVE_POINTS {
+            return self.speeds[CURVE_POINTS - 1] as u32;
+        }
+        let (low, high) = (self.speeds[i] as u32, self.speeds[i + 1] as u32);
+        low + (high - low) * (duty % CURVE_STEP) as u32 / CURVE_STEP as u32
+    }
+
+    pub fn write(&self, w: &mut Writer) {
+        w.u8(self.dead_band);
+        for speed in self.speeds {
+            w.u16(speed);
+        }
+    }
+
+    pub fn read(r: &mut Reader) -> Option<Self> {
+        let mut curve = Self {
+            dead_band: r.u8()?,
+            ..Default::default()
+        };
+        for speed in curve.speeds.iter_mut() {
+            *speed = r.u16()?;
+        }
+        Some(curve)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MotorCalibration {
+    pub left_forward: SpeedCurve,
+    pub left_backwards: SpeedCurve,
+    pub right_forward: SpeedCurve,
+    pub right_backwards: SpeedCurve,
+}
+
+impl MotorCalibration {
+    pub fn curve(&self, side: Side, direction: Direction) -> &SpeedCurve {
+        match (side, direction) {
+            (Side::Left, Direction::Forward) => &self.left_forward,
+            (Side::Left, Direction::Backwards) => &self.left_backwards,
+            (Side::Right, Direction::Forward) => &self.right_forward,
+            (Side::Right, Direction::Backwards) => &self.right_backwards,
+        }
+    }
+
+    pub fn write(&self, w: &mut Writer) {
+        for (side, direction) in SWEEP_ORDER {
+            self.curve(side, direction).write(w);
+        }
+    }
+
+    pub fn read(r: &mut Reader) -> Option<Self> {
+        let mut calibration = Self::default();
+        for (side, direction) in SWEEP_ORDER {
+            *calibration.curve_mut(side, direction) = SpeedCurve::read(r)?;
+        }
+        Some(calibration)
+    }
+
+    fn curve_mut(&mut self, side: Side, direction: Direction) -> &mut SpeedCurve {
+        match (side, direction) {
+            (Side::Left, Direction::Forward) => &mut self.left_forward,
+            (Side::Left, Direction::Backwards) => &mut self.left_backwards,
+            (Side::Right, Direction::Forward) => &mut self.right_forward,
+            (Side::Right, Direction::Backwards) => &mut self.right_backwards,
+        }
+    }
+}
+
+// Progress of the duty sweep. MotorsSm drives the wheels, this only keeps track
+// of where the sweep is and collects the results
+#[derive(Debug, Clone, Default)]
+pub struct Sweep {
+    wheel: usize,
+    point: usize,
+    result: MotorCalibration,
+}
+
+impl Sweep {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Wheel, direction and duty to measure next, None once the sweep is done
+    pub fn current(&self) -> Option<(Side, Direction, u8)> {
+        let (side, direction) = *SWEEP_ORDER.get(self.wheel)?;
+        Some((side, direction, self.point as u8 * CURVE_STEP))
+    }
+
+    // Records the speed measured at the current point and moves to the next one.
+    // Returns true when the sweep moves on to another wheel or direction
+    pub fn record(&mut self, speed: u16) -> bool {
+        let Some((side, direction, duty)) = self.current() else {
+            return false;
+        };
+        let curve = self.result.curve_mut(side, direction);
+        if speed > 0 && curve.speeds[..self.point].iter().all(|&s| s == 0) {
+            curve.dead_band = duty;
+        }
+        // Noise must not make the curve go down, duty() relies on that
+        let prev = if self.point > 0 {
+            curve.speeds[self.point - 1]
+        } else {
+            0
+        };
+        curve.speeds[self.point] = speed.max(prev);
+
+        self.point += 1;
+        if self.point == CURVE_POINTS {
+            self.point = 0;
+            self.wheel += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn result(&self) -> MotorCalibration {
+        self.result
+    }
+}
@@ -1,10 +1,15 @@
+#[cfg(target_arch = "riscv32")]
 use crate::comm::{SENSOR_CHANNEL, SensorMessage};
+#[cfg(target_arch = "riscv32")]
 use embassy_time::{Duration, Timer};
+#[cfg(target_arch = "riscv32")]
 use esp_hal::i2c;
 use smart_leds::RGB;
-use tcs3472::{AllChannelMeasurement, Tcs3472};
+use tcs3472::AllChannelMeasurement;
+#[cfg(target_arch = "riscv32")]
+use tcs3472::Tcs3472;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Color {
     Black,
     Blue,
@@ -52,6 +57,26 @@ const CHANNEL_HIGH_THRESHOLD: f32 = 0.7;
 const CHANNEL_LOW_THRESHOLD: f32 = 0.3;
 
 impl Color {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Color::Black),
+            1 => Some(Color::Blue),
+            2 => Some(Color::Red),
+            3 => Some(Color::Magenta),
+            4 => Some(Color::Green),
+            5 => Some(Color::Cyan),
+            6 => Some(Color::Yellow),
+            7 => Some(Color::White),
+            8 => Some(Color::Orange),
+            9 => Some(Color::Unknown),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
     pub fn to_rgb(self) -> RGB<u8> {
         match self {
             Color::Black => RGB::new(0, 0, 0),
@@ -150,6 +175,7 @@ impl Color {
     }
 }
 
+#[cfg(target_arch = "riscv32")]
 #[embassy_executor::task]
 pub async fn color_task(i2c: i2c::master::I2c<'static, esp_hal::Async>) {
     let mut sensor = Tcs3472::new(i2c);
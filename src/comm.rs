@@ -2,14 +2,20 @@ use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
 
 use crate::color::Color;
-use crate::telemetry::TelemetryPacket;
+use crate::motors::MotionEvent;
+use crate::remote::RemoteCommand;
+use crate::telemetry::Telemetry;
 
 pub static SENSOR_CHANNEL: Channel<CriticalSectionRawMutex, SensorMessage, 4> = Channel::new();
-pub static TELEMETRY_CHANNEL: Channel<CriticalSectionRawMutex, TelemetryPacket, 4> = Channel::new();
+pub static TELEMETRY_CHANNEL: Channel<CriticalSectionRawMutex, Telemetry, 4> = Channel::new();
 
 #[derive(Debug, Clone, Copy)]
 pub enum SensorMessage {
     Color(Color),
     Distance(u16),
     Voltage(u16),
+    // Published by the main loop when MotorsSm finishes a move
+    Motion(MotionEvent),
+    // Received over ESP-NOW
+    Remote(RemoteCommand),
 }
@@ -1,17 +1,68 @@
 use crate::color::Color;
 use crate::comm::SensorMessage;
-use crate::motors::MotorsSmCommand;
+use crate::motors::{MotionOutcome, MotorsSmCommand};
+use crate::program::{Action, Program, Replay, ReplayStep, Tape};
+use crate::remote::RemoteCommand;
+use crate::surface::Surface;
 
+#[derive(PartialEq)]
 enum ControlState {
     BatteryLow,
     Blocked,
     Normal,
+    // End of program mat was reached, mats are ignored until the robot is blocked
+    Finished,
+    // Robot is pushed over the mats, which are recorded instead of driven
+    Recording,
+}
+
+// Whether the last mat left the robot on it, so seeing it again is not a new command
+#[derive(PartialEq)]
+enum OnMat {
+    No,
+    // Turn or pause, moving off the mat is part of the same action
+    Action,
+    // Repeat or loop end
+    Replay,
 }
 
 pub struct ControlSm {
     state: ControlState,
     distance_samples_cnt: u32,
-    last_turn: bool,
+    on_mat: OnMat,
+    // Program state changed by the mats
+    speed: u8,
+    reversed: bool,
+    // White mat was seen, the next mat has its shifted meaning.
+    // Holds the program length before the White mat
+    shift: Option<usize>,
+    // Executed actions, loops and repeats replay them
+    program: Program,
+    replay: Option<Replay>,
+    loop_start: Option<usize>,
+    // How many actions the repeat mat replays, and how many times
+    repeat_actions: u8,
+    repeat_times: u8,
+    // Recorded mats, and the next one to run while they are run
+    tape: Tape,
+    run: Option<usize>,
+    // Color seen for steady_samples readings in a row, and the last steady one
+    steady_color: Option<Color>,
+    steady_samples: u32,
+    last_steady: Option<Color>,
+    // Tape was recorded or edited, taken by take_program_changed()
+    program_changed: bool,
+    // A move was requested and MotorsSm hasn't reported its end yet
+    moving: bool,
+    // Move was paused from the remote, it still counts as moving
+    paused: bool,
+    // Surface card seen while blocked, taken by take_surface()
+    surface: Option<Surface>,
+    // Sensor saw no mat since the robot was blocked, so it was picked up and a color is a card
+    card_ready: bool,
+    // Color held under the sensor for card_samples readings in a row
+    card: Option<Color>,
+    card_samples: u32,
 }
 
 const BATTERY_LOW: u16 = 3200; // 3200 mV
@@ -19,9 +70,18 @@ const NO_BATTERY: u16 = 200; // 200 mV
 const DISTANCE_CLOSE: u16 = 7; // cm
 const DISTANCE_SAMPLES: u32 = 3;
 
-const FORWARD_DELAY: u64 = 420;
-const LEFT_DELAY: u64 = 100;
-const RIGHT_DELAY: u64 = 100;
+const FORWARD_DISTANCE: u32 = 100; // mm, one mat
+const TURN_ANGLE: u16 = 90; // deg
+const SPEED: u8 = 100; // % of nominal speed, at the start of a program
+const MIN_SPEED: u8 = 50;
+const MAX_SPEED: u8 = 150;
+const SPEED_STEP: u8 = 25;
+const U_TURN_ANGLE: u16 = 180; // deg
+const PAUSE_TIME: u64 = 2000; // ms
+const REPEAT_ACTIONS: u8 = 2;
+const REPEAT_TIMES: u8 = 3;
+const RECORD_SAMPLES: u32 = 3;
+const CARD_SAMPLES: u32 = 10;
 const _BACKWARDS_DELAY: u64 = 1000;
 
 impl ControlSm {
@@ -29,17 +89,479 @@ impl ControlSm {
         Self {
             state: ControlState::Blocked,
             distance_samples_cnt: 0,
-            last_turn: false,
+            on_mat: OnMat::No,
+            speed: SPEED,
+            reversed: false,
+            shift: None,
+            program: Program::new(),
+            replay: None,
+            loop_start: None,
+            repeat_actions: REPEAT_ACTIONS,
+            repeat_times: REPEAT_TIMES,
+            tape: Tape::new(),
+            run: None,
+            steady_color: None,
+            steady_samples: 0,
+            last_steady: None,
+            program_changed: false,
+            moving: false,
+            paused: false,
+            surface: None,
+            card_ready: false,
+            card: None,
+            card_samples: 0,
+        }
+    }
+
+    fn reset_program(&mut self) {
+        self.on_mat = OnMat::No;
+        self.speed = SPEED;
+        self.reversed = false;
+        self.shift = None;
+        self.program.clear();
+        self.replay = None;
+        self.loop_start = None;
+        self.run = None;
+    }
+
+    pub fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    // Mats saved before the last power-off
+    pub fn set_tape(&mut self, tape: Tape) {
+        self.tape = tape;
+        self.program_changed = true;
+    }
+
+    // Whether the tape changed since the last call
+    pub fn take_program_changed(&mut self) -> bool {
+        core::mem::take(&mut self.program_changed)
+    }
+
+    // Motors stay off while the robot is pushed over the mats
+    fn start_recording(&mut self) -> Option<MotorsSmCommand> {
+        if self.state == ControlState::BatteryLow {
+            log::warn!("Battery is low, not recording");
+            return None;
+        }
+        log::info!("Recording mats");
+        self.reset_program();
+        self.tape.clear();
+        self.program_changed = true;
+        self.steady_color = None;
+        self.steady_samples = 0;
+        self.last_steady = None;
+        self.distance_samples_cnt = 0;
+        self.state = ControlState::Recording;
+        self.stop_if_moving()
+    }
+
+    // Remote can drive the robot in states without a program or mats to stop it
+    fn stop_if_moving(&self) -> Option<MotorsSmCommand> {
+        if self.moving {
+            Some(MotorsSmCommand::EmergencyStop)
+        } else {
+            None
+        }
+    }
+
+    // Robot stays where it was pushed to, until it is blocked
+    fn stop_recording(&mut self) {
+        if self.state == ControlState::Recording {
+            log::info!("Recorded {} mats", self.tape.len());
+            self.state = ControlState::Finished;
+        }
+    }
+
+    // A mat is recorded once its color is steady, and again only after another color,
+    // so the gap between two mats of the same color separates them
+    fn record_mat(&mut self, color: Color) {
+        if self.steady_color == Some(color) {
+            self.steady_samples = (self.steady_samples + 1).min(RECORD_SAMPLES);
+        } else {
+            self.steady_color = Some(color);
+            self.steady_samples = 1;
+        }
+        if self.steady_samples < RECORD_SAMPLES || self.last_steady == Some(color) {
+            return;
+        }
+        self.last_steady = Some(color);
+        if matches!(color, Color::Black | Color::Unknown) {
+            return;
+        }
+        if self.tape.push(color) {
+            log::info!("Recorded {:?}", color);
+            self.program_changed = true;
+        } else {
+            log::warn!("Tape is full, {:?} not recorded", color);
+        }
+    }
+
+    fn run_program(&mut self) -> Option<MotorsSmCommand> {
+        // Blocked robot has something in front of it, it runs once that is gone
+        if self.moving || matches!(self.state, ControlState::BatteryLow | ControlState::Blocked) {
+            log::warn!("Can't run the program now");
+            return None;
+        }
+        if self.tape.is_empty() {
+            log::warn!("Nothing recorded");
+            return None;
+        }
+        log::info!("Running {} recorded mats", self.tape.len());
+        self.reset_program();
+        self.distance_samples_cnt = 0;
+        self.state = ControlState::Normal;
+        self.run = Some(0);
+        self.run_next()
+    }
+
+    // Feeds the recorded mats to the program as if the robot saw them
+    fn run_next(&mut self) -> Option<MotorsSmCommand> {
+        let mut index = self.run?;
+        while self.state == ControlState::Normal {
+            // Turns and pauses see their mat again to move off it
+            let color = if self.on_mat == OnMat::No {
+                index += 1;
+                self.tape.get(index - 1)
+            } else {
+                self.tape.get(index.saturating_sub(1))
+            };
+            let Some(color) = color else {
+                log::info!("Recorded program finished");
+                self.state = ControlState::Finished;
+                break;
+            };
+            if let Some(cmd) = self.process_mat(color) {
+                self.run = Some(index);
+                return Some(cmd);
+            }
+        }
+        self.run = None;
+        None
+    }
+
+    fn edited(&mut self, ok: bool) {
+        if ok {
+            self.program_changed = true;
+        } else {
+            log::warn!("Program step is out of range");
+        }
+    }
+
+    fn process_remote(&mut self, cmd: RemoteCommand) -> Option<MotorsSmCommand> {
+        match cmd {
+            RemoteCommand::SetRepeat { actions, times } => self.set_repeat(actions, times),
+            RemoteCommand::Record(true) => return self.start_recording(),
+            RemoteCommand::Record(false) => self.stop_recording(),
+            RemoteCommand::Run => return self.run_program(),
+            // Running program reads the tape as it goes
+            RemoteCommand::SetStep { .. }
+            | RemoteCommand::InsertStep { .. }
+            | RemoteCommand::DeleteStep { .. }
+                if self.run.is_some() =>
+            {
+                log::warn!("Program is running, not edited");
+            }
+            RemoteCommand::SetStep { color, .. } | RemoteCommand::InsertStep { color, .. }
+                if matches!(color, Color::Black | Color::Unknown) =>
+            {
+                log::warn!("{:?} is not a mat", color);
+            }
+            RemoteCommand::SetStep { index, color } => {
+                let ok = self.tape.set(index as usize, color);
+                self.edited(ok);
+            }
+            RemoteCommand::InsertStep { index, color } => {
+                let ok = self.tape.insert(index as usize, color);
+                self.edited(ok);
+            }
+            RemoteCommand::DeleteStep { index } => {
+                let ok = self.tape.remove(index as usize);
+                self.edited(ok);
+            }
+            RemoteCommand::Pause if self.moving && !self.paused => {
+                self.paused = true;
+                return Some(MotorsSmCommand::Pause);
+            }
+            RemoteCommand::Resume if self.paused => {
+                self.paused = false;
+                return Some(MotorsSmCommand::Resume);
+            }
+            // Paused move has already stopped and reports no event, so it is dropped at once
+            RemoteCommand::Stop(_) if self.paused => return Some(MotorsSmCommand::EmergencyStop),
+            RemoteCommand::Stop(mode) if self.moving => return Some(MotorsSmCommand::Stop(mode)),
+            RemoteCommand::Arc {
+                radius_mm,
+                length_mm,
+                speed,
+            } => return self.drive_arc(radius_mm, length_mm, speed),
+            RemoteCommand::Calibrate => return self.spin_in_place(MotorsSmCommand::Calibrate),
+            #[cfg(feature = "pid")]
+            RemoteCommand::AutoTune => return self.spin_in_place(MotorsSmCommand::AutoTune),
+            #[cfg(not(feature = "pid"))]
+            RemoteCommand::AutoTune => log::warn!("Auto-tune needs the pid feature"),
+            RemoteCommand::Pause | RemoteCommand::Resume | RemoteCommand::Stop(_) => {
+                log::warn!("Nothing to {:?}", cmd);
+            }
+            // Handled by the main loop, which owns the flash and the motor config
+            RemoteCommand::SelectSurface(_)
+            | RemoteCommand::SaveProgram
+            | RemoteCommand::ViewProgram
+            | RemoteCommand::ClearFault => {}
+        }
+        None
+    }
+
+    // Remote drives only a robot that is waiting for the mats or done with them
+    fn drive_arc(&mut self, radius_mm: i16, length_mm: u16, speed: u8) -> Option<MotorsSmCommand> {
+        if self.moving || !matches!(self.state, ControlState::Normal | ControlState::Finished) {
+            log::warn!("Can't drive now");
+            return None;
+        }
+        Some(MotorsSmCommand::Arc {
+            radius_mm: radius_mm as i32,
+            length_mm: length_mm as u32,
+            speed,
+        })
+    }
+
+    // Calibration and tuning spin the robot, so they don't interrupt a program or recording
+    fn spin_in_place(&mut self, cmd: MotorsSmCommand) -> Option<MotorsSmCommand> {
+        // Only where an obstacle or a low battery stops the robot, a blocked robot has
+        // something right in front of it already
+        if self.moving || !matches!(self.state, ControlState::Normal | ControlState::Finished) {
+            log::warn!("Can't start {:?} now", cmd);
+            return None;
+        }
+        log::info!("Starting {:?}, the robot spins in place", cmd);
+        Some(cmd)
+    }
+
+    pub fn set_repeat(&mut self, actions: u8, times: u8) {
+        log::info!("Repeat mat replays {} actions {} times", actions, times);
+        self.repeat_actions = actions;
+        self.repeat_times = times;
+    }
+
+    fn record(&mut self, action: Action) {
+        if !self.program.push(action) {
+            log::warn!("Program is full, action not recorded");
+        }
+    }
+
+    fn start_replay(&mut self, start: usize, end: usize) -> Option<MotorsSmCommand> {
+        log::info!(
+            "Replaying actions {}..{} {} times",
+            start,
+            end,
+            self.repeat_times
+        );
+        self.replay = Some(Replay::new(start, end, self.repeat_times));
+        self.replay_next()
+    }
+
+    // Replayed actions are recorded too, so a later repeat can include them
+    fn replay_next(&mut self) -> Option<MotorsSmCommand> {
+        let step = self.replay.as_mut()?.next(&self.program);
+        match step {
+            Some(ReplayStep::Action(action)) => {
+                self.record(action);
+                Some(action.cmd)
+            }
+            Some(ReplayStep::MoveOff(cmd)) => Some(cmd),
+            None => {
+                self.replay = None;
+                None
+            }
         }
     }
 
+    // One mat ahead, or back while reversed
+    fn next_mat(&self) -> MotorsSmCommand {
+        if self.reversed {
+            MotorsSmCommand::BackwardsDistance(FORWARD_DISTANCE, self.speed)
+        } else {
+            MotorsSmCommand::ForwardDistance(FORWARD_DISTANCE, self.speed)
+        }
+    }
+
+    // Every mat has to get the robot off it, otherwise it would be read again. Mats that
+    // turn or pause do that the next time they are seen
+    fn process_mat(&mut self, color: Color) -> Option<MotorsSmCommand> {
+        let in_place = match color {
+            Color::Red | Color::Orange | Color::Blue | Color::Cyan | Color::Yellow => true,
+            Color::Magenta | Color::Green | Color::White => false,
+            _ => return None,
+        };
+        let on_mat = core::mem::replace(&mut self.on_mat, OnMat::No);
+        if in_place && on_mat != OnMat::No {
+            let cmd = self.next_mat();
+            if on_mat == OnMat::Action {
+                self.program.set_move_off(cmd);
+            } else {
+                self.record(Action::new(cmd));
+            }
+            return Some(cmd);
+        }
+
+        let cmd = match (self.shift.take(), color) {
+            (Some(_), Color::Magenta) => {
+                self.speed = (self.speed + SPEED_STEP).min(MAX_SPEED);
+                log::info!("Speed up to {}%", self.speed);
+                self.next_mat()
+            }
+            (Some(_), Color::Green) => {
+                self.speed = self.speed.saturating_sub(SPEED_STEP).max(MIN_SPEED);
+                log::info!("Slow down to {}%", self.speed);
+                self.next_mat()
+            }
+            (Some(_), Color::Yellow) => {
+                log::info!("End of program");
+                self.state = ControlState::Finished;
+                return None;
+            }
+            // Loop start mat is the first one of the loop
+            (Some(_), Color::Blue) => {
+                self.loop_start = Some(self.program.len());
+                self.next_mat()
+            }
+            // Mats up to the White one before the loop end are replayed
+            (Some(end), Color::Red | Color::Orange) => match self.loop_start.take() {
+                Some(start) => {
+                    self.on_mat = OnMat::Replay;
+                    return self.start_replay(start, end);
+                }
+                None => {
+                    log::warn!("Loop end without a loop start");
+                    self.next_mat()
+                }
+            },
+            (Some(end), Color::Cyan) => {
+                self.on_mat = OnMat::Replay;
+                let start = end.saturating_sub(self.repeat_actions as usize);
+                return self.start_replay(start, end);
+            }
+            (_, Color::White) => {
+                self.shift = Some(self.program.len());
+                self.next_mat()
+            }
+            (_, Color::Magenta) => self.next_mat(),
+            (_, Color::Green) => {
+                self.reversed = !self.reversed;
+                self.next_mat()
+            }
+            (_, Color::Red | Color::Orange) => {
+                self.on_mat = OnMat::Action;
+                MotorsSmCommand::TurnLeft(TURN_ANGLE, self.speed)
+            }
+            (_, Color::Blue) => {
+                self.on_mat = OnMat::Action;
+                MotorsSmCommand::TurnRight(TURN_ANGLE, self.speed)
+            }
+            (_, Color::Cyan) => {
+                self.on_mat = OnMat::Action;
+                MotorsSmCommand::TurnRight(U_TURN_ANGLE, self.speed)
+            }
+            (_, Color::Yellow) => {
+                self.on_mat = OnMat::Action;
+                MotorsSmCommand::Wait(PAUSE_TIME)
+            }
+            _ => return None,
+        };
+        self.record(Action::new(cmd));
+        Some(cmd)
+    }
+
+    // Surface selected with a calibration card, each selection is returned once
+    pub fn take_surface(&mut self) -> Option<Surface> {
+        self.surface.take()
+    }
+
     pub fn process_event(&mut self, message: SensorMessage) -> Option<MotorsSmCommand> {
+        if let SensorMessage::Motion(event) = message {
+            // Paused move reports another event once it is resumed and finished
+            if event.outcome == MotionOutcome::Paused {
+                return None;
+            }
+            self.paused = false;
+            // Replay goes on while moves complete, anything else ends it
+            let cmd = if event.outcome == MotionOutcome::Complete {
+                self.replay_next().or_else(|| self.run_next())
+            } else {
+                self.replay = None;
+                self.run = None;
+                None
+            };
+            self.moving = cmd.is_some();
+            return cmd;
+        }
+
+        let cmd = match message {
+            SensorMessage::Remote(cmd) => self.process_remote(cmd),
+            _ => self.process_sensor(message),
+        };
+        match cmd {
+            // Pending move is dropped, so there may be no event for it
+            Some(MotorsSmCommand::EmergencyStop) => {
+                self.moving = false;
+                self.paused = false;
+                self.replay = None;
+                self.run = None;
+            }
+            Some(_) => self.moving = true,
+            None => {}
+        }
+        cmd
+    }
+
+    // MotorsSm refused the last command, so no event will end it. The program driving it stops
+    pub fn command_failed(&mut self) {
+        self.moving = false;
+        self.paused = false;
+        self.replay = None;
+        self.run = None;
+    }
+
+    fn block(&mut self) {
+        self.state = ControlState::Blocked;
+        self.distance_samples_cnt = 0;
+        self.card_ready = false;
+        self.card = None;
+    }
+
+    // Cards are the same colors as the mats. A color only counts as a card once the robot was
+    // picked up, so the sensor saw no mat, and the card is held under it for a while.
+    // Booting or being blocked on a mat doesn't switch the surface then
+    fn show_card(&mut self, color: Color) {
+        if matches!(color, Color::Black | Color::Unknown) {
+            self.card_ready = true;
+            self.card = None;
+            return;
+        }
+        if !self.card_ready {
+            return;
+        }
+        if self.card == Some(color) {
+            self.card_samples += 1;
+        } else {
+            self.card = Some(color);
+            self.card_samples = 1;
+        }
+        if self.card_samples == CARD_SAMPLES
+            && let Some(surface) = Surface::from_card(color)
+        {
+            log::info!("{} card shown", surface.name());
+            self.surface = Some(surface);
+        }
+    }
+
+    fn process_sensor(&mut self, message: SensorMessage) -> Option<MotorsSmCommand> {
         match self.state {
             ControlState::BatteryLow => match message {
                 SensorMessage::Voltage(v) => {
                     if v > BATTERY_LOW {
-                        self.state = ControlState::Blocked;
-                        self.distance_samples_cnt = 0;
+                        self.block();
                     }
                     None
                 }
@@ -64,38 +586,62 @@ impl ControlSm {
                     }
 
                     if self.distance_samples_cnt == DISTANCE_SAMPLES {
-                        self.distance_samples_cnt = 0;
-                        self.state = ControlState::Blocked;
+                        self.block();
                         Some(MotorsSmCommand::EmergencyStop)
                     } else {
                         None
                     }
                 }
-                SensorMessage::Color(c) => match c {
-                    Color::Magenta => {
-                        self.last_turn = false;
-                        Some(MotorsSmCommand::Forward(FORWARD_DELAY))
+                // Colors seen while crossing the mats are not commands,
+                // and a recorded program runs without the mats
+                SensorMessage::Color(_) if self.moving || self.run.is_some() => None,
+                SensorMessage::Color(c) => self.process_mat(c),
+                SensorMessage::Motion(_) | SensorMessage::Remote(_) => None,
+            },
+            ControlState::Finished => match message {
+                SensorMessage::Voltage(v) => {
+                    if (NO_BATTERY..BATTERY_LOW).contains(&v) {
+                        self.state = ControlState::BatteryLow;
+                        self.distance_samples_cnt = 0;
+                        self.stop_if_moving()
+                    } else {
+                        None
                     }
-                    Color::Red | Color::Orange => {
-                        if self.last_turn {
-                            self.last_turn = false;
-                            Some(MotorsSmCommand::Forward(FORWARD_DELAY))
-                        } else {
-                            self.last_turn = true;
-                            Some(MotorsSmCommand::Left(LEFT_DELAY))
-                        }
+                }
+                // Blocking the robot starts a new program
+                SensorMessage::Distance(d) => {
+                    if d < DISTANCE_CLOSE {
+                        self.distance_samples_cnt =
+                            (self.distance_samples_cnt + 1).clamp(0, DISTANCE_SAMPLES);
+                    } else {
+                        self.distance_samples_cnt = 0;
                     }
-                    Color::Blue => {
-                        if self.last_turn {
-                            self.last_turn = false;
-                            Some(MotorsSmCommand::Forward(FORWARD_DELAY))
-                        } else {
-                            self.last_turn = true;
-                            Some(MotorsSmCommand::Right(RIGHT_DELAY))
-                        }
+
+                    if self.distance_samples_cnt == DISTANCE_SAMPLES {
+                        self.reset_program();
+                        self.block();
+                        self.stop_if_moving()
+                    } else {
+                        None
                     }
-                    _ => None,
-                },
+                }
+                _ => None,
+            },
+            ControlState::Recording => match message {
+                SensorMessage::Voltage(v) => {
+                    if (NO_BATTERY..BATTERY_LOW).contains(&v) {
+                        self.state = ControlState::BatteryLow;
+                        self.distance_samples_cnt = 0;
+                        self.stop_if_moving()
+                    } else {
+                        None
+                    }
+                }
+                SensorMessage::Color(c) => {
+                    self.record_mat(c);
+                    None
+                }
+                _ => None,
             },
             ControlState::Blocked => match message {
                 SensorMessage::Voltage(v) => {
@@ -117,13 +663,143 @@ impl ControlSm {
 
                     if self.distance_samples_cnt == DISTANCE_SAMPLES {
                         self.distance_samples_cnt = 0;
-                        self.last_turn = false;
+                        self.on_mat = OnMat::No;
                         self.state = ControlState::Normal;
                     }
                     None
                 }
+                SensorMessage::Color(c) => {
+                    self.show_card(c);
+                    None
+                }
                 _ => None,
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn show(sm: &mut ControlSm, color: Color, samples: u32) {
+        for _ in 0..samples {
+            assert!(sm.process_event(SensorMessage::Color(color)).is_none());
+        }
+    }
+
+    #[test]
+    fn mat_under_blocked_robot_is_not_a_card() {
+        let mut sm = ControlSm::init();
+        show(&mut sm, Color::Green, 2 * CARD_SAMPLES);
+        assert!(sm.take_surface().is_none());
+    }
+
+    #[test]
+    fn card_is_taken_after_pick_up() {
+        let mut sm = ControlSm::init();
+        show(&mut sm, Color::Cyan, 2);
+        show(&mut sm, Color::Unknown, 2);
+        show(&mut sm, Color::Green, CARD_SAMPLES - 1);
+        assert!(sm.take_surface().is_none());
+        show(&mut sm, Color::Green, CARD_SAMPLES);
+        assert_eq!(sm.take_surface(), Some(Surface::Table));
+        assert!(sm.take_surface().is_none());
+    }
+
+    #[test]
+    fn program_edits_are_checked() {
+        let mut sm = ControlSm::init();
+        let edit = |color| SensorMessage::Remote(RemoteCommand::SetStep { index: 0, color });
+        sm.process_event(edit(Color::Black));
+        sm.process_event(edit(Color::Unknown));
+        assert!(sm.tape().is_empty());
+        sm.process_event(edit(Color::Blue));
+        assert_eq!(sm.tape().colors(), &[Color::Blue]);
+
+        for _ in 0..DISTANCE_SAMPLES {
+            sm.process_event(SensorMessage::Distance(DISTANCE_CLOSE + 1));
+        }
+        assert!(
+            sm.process_event(SensorMessage::Remote(RemoteCommand::Run))
+                .is_some()
+        );
+        sm.process_event(edit(Color::Magenta));
+        sm.process_event(SensorMessage::Remote(RemoteCommand::DeleteStep {
+            index: 0,
+        }));
+        assert_eq!(sm.tape().colors(), &[Color::Blue]);
+    }
+
+    #[test]
+    fn blocked_robot_doesnt_run_program() {
+        let mut sm = ControlSm::init();
+        let mut tape = Tape::new();
+        tape.push(Color::Magenta);
+        sm.set_tape(tape);
+        let run = SensorMessage::Remote(RemoteCommand::Run);
+        assert!(sm.process_event(run).is_none());
+        for _ in 0..DISTANCE_SAMPLES {
+            sm.process_event(SensorMessage::Distance(DISTANCE_CLOSE + 1));
+        }
+        assert!(matches!(
+            sm.process_event(run),
+            Some(MotorsSmCommand::ForwardDistance(..))
+        ));
+    }
+
+    #[test]
+    fn blocked_robot_doesnt_spin() {
+        let mut sm = ControlSm::init();
+        let calibrate = SensorMessage::Remote(RemoteCommand::Calibrate);
+        assert!(sm.process_event(calibrate).is_none());
+        for _ in 0..DISTANCE_SAMPLES {
+            sm.process_event(SensorMessage::Distance(DISTANCE_CLOSE + 1));
+        }
+        assert!(matches!(
+            sm.process_event(calibrate),
+            Some(MotorsSmCommand::Calibrate)
+        ));
+    }
+
+    // Unblocked robot that recorded no mats, driven by the remote
+    fn finished_and_driving() -> ControlSm {
+        let mut sm = ControlSm::init();
+        for _ in 0..DISTANCE_SAMPLES {
+            sm.process_event(SensorMessage::Distance(DISTANCE_CLOSE + 1));
+        }
+        sm.process_event(SensorMessage::Remote(RemoteCommand::Record(true)));
+        sm.process_event(SensorMessage::Remote(RemoteCommand::Record(false)));
+        let arc = RemoteCommand::Arc {
+            radius_mm: 200,
+            length_mm: 500,
+            speed: 100,
+        };
+        assert!(matches!(
+            sm.process_event(SensorMessage::Remote(arc)),
+            Some(MotorsSmCommand::Arc { .. })
+        ));
+        sm
+    }
+
+    #[test]
+    fn remote_move_stops_for_obstacle_and_low_battery() {
+        let mut sm = finished_and_driving();
+        for _ in 0..DISTANCE_SAMPLES - 1 {
+            assert!(
+                sm.process_event(SensorMessage::Distance(DISTANCE_CLOSE - 1))
+                    .is_none()
+            );
+        }
+        assert!(matches!(
+            sm.process_event(SensorMessage::Distance(DISTANCE_CLOSE - 1)),
+            Some(MotorsSmCommand::EmergencyStop)
+        ));
+
+        let mut sm = finished_and_driving();
+        assert!(matches!(
+            sm.process_event(SensorMessage::Voltage(BATTERY_LOW - 1)),
+            Some(MotorsSmCommand::EmergencyStop)
+        ));
+    }
+}
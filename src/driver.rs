@@ -0,0 +1,53 @@
+// H-bridges only build for the robot, the mock only for host tests
+#[cfg(target_arch = "riscv32")]
+mod hbridge;
+#[cfg(test)]
+mod mock;
+
+#[cfg(target_arch = "riscv32")]
+pub use hbridge::{Drv8833, LedcHBridge, PwmDir, Tb6612};
+#[cfg(test)]
+pub use mock::{DriverCall, MockDriver};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {
+    Forward,
+    Backwards,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DriverError {
+    // LEDC rejected a duty
+    #[cfg(target_arch = "riscv32")]
+    Ledc(esp_hal::ledc::channel::Error),
+    // Injected by MockDriver
+    #[cfg(test)]
+    Mock,
+}
+
+// Everything Motors needs from an H-bridge. Duty is in percent
+pub trait MotorDriver {
+    fn set(&mut self, side: Side, direction: Direction, duty: u8) -> Result<(), DriverError>;
+    // Shorts the motor, so it stops quickly
+    fn brake(&mut self, side: Side) -> Result<(), DriverError>;
+    // Disconnects the motor, so it spins down freely
+    fn coast(&mut self, side: Side) -> Result<(), DriverError>;
+
+    // Turns all outputs off after a fault. Both sides are tried even if one fails
+    fn disable(&mut self) -> Result<(), DriverError> {
+        let left = self.coast(Side::Left);
+        let right = self.coast(Side::Right);
+        left.and(right)
+    }
+
+    // Undoes disable() once the fault is cleared. Outputs disabled by coasting need nothing
+    fn enable(&mut self) -> Result<(), DriverError> {
+        Ok(())
+    }
+}
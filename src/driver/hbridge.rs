@@ -0,0 +1,311 @@
+use esp_hal::gpio::{Level, Output};
+use esp_hal::ledc::{self, channel::ChannelIFace};
+
+use super::{Direction, DriverError, MotorDriver, Side};
+
+type Pwm<'a> = ledc::channel::Channel<'a, ledc::LowSpeed>;
+
+impl From<ledc::channel::Error> for DriverError {
+    fn from(e: ledc::channel::Error) -> Self {
+        DriverError::Ledc(e)
+    }
+}
+
+// LM298N-like H-bridge with a PWM signal on both inputs of each motor
+pub struct LedcHBridge<'a> {
+    left_1: Pwm<'a>,
+    left_2: Pwm<'a>,
+    right_1: Pwm<'a>,
+    right_2: Pwm<'a>,
+}
+
+impl<'a> LedcHBridge<'a> {
+    pub fn new(
+        left_1: Pwm<'a>,
+        left_2: Pwm<'a>,
+        right_1: Pwm<'a>,
+        right_2: Pwm<'a>,
+    ) -> Result<Self, DriverError> {
+        left_1.set_duty(0)?;
+        left_2.set_duty(0)?;
+        right_1.set_duty(0)?;
+        right_2.set_duty(0)?;
+        Ok(Self {
+            left_1,
+            left_2,
+            right_1,
+            right_2,
+        })
+    }
+
+    fn inputs(&self, side: Side) -> [&Pwm<'a>; 2] {
+        match side {
+            Side::Left => [&self.left_1, &self.left_2],
+            Side::Right => [&self.right_1, &self.right_2],
+        }
+    }
+
+    // Input driven with PWM and input held low for a direction
+    fn active_idle(&self, side: Side, direction: Direction) -> [&Pwm<'a>; 2] {
+        let [in1, in2] = self.inputs(side);
+        match direction {
+            Direction::Forward => [in1, in2],
+            Direction::Backwards => [in2, in1],
+        }
+    }
+}
+
+impl MotorDriver for LedcHBridge<'_> {
+    fn set(&mut self, side: Side, direction: Direction, duty: u8) -> Result<(), DriverError> {
+        let [active, idle] = self.active_idle(side, direction);
+        idle.set_duty(0)?;
+        active.set_duty(duty)?;
+        Ok(())
+    }
+
+    fn brake(&mut self, side: Side) -> Result<(), DriverError> {
+        for input in self.inputs(side) {
+            input.set_duty(100)?;
+        }
+        Ok(())
+    }
+
+    fn coast(&mut self, side: Side) -> Result<(), DriverError> {
+        for input in self.inputs(side) {
+            input.set_duty(0)?;
+        }
+        Ok(())
+    }
+}
+
+// DRV8833-like H-bridge with PWM on both inputs, driven in slow decay mode: one input is held
+// high and the other one is PWMed low, so the motor brakes instead of coasting between pulses
+pub struct Drv8833<'a> {
+    left_1: Pwm<'a>,
+    left_2: Pwm<'a>,
+    right_1: Pwm<'a>,
+    right_2: Pwm<'a>,
+}
+
+impl<'a> Drv8833<'a> {
+    pub fn new(
+        left_1: Pwm<'a>,
+        left_2: Pwm<'a>,
+        right_1: Pwm<'a>,
+        right_2: Pwm<'a>,
+    ) -> Result<Self, DriverError> {
+        left_1.set_duty(0)?;
+        left_2.set_duty(0)?;
+        right_1.set_duty(0)?;
+        right_2.set_duty(0)?;
+        Ok(Self {
+            left_1,
+            left_2,
+            right_1,
+            right_2,
+        })
+    }
+
+    fn inputs(&self, side: Side) -> [&Pwm<'a>; 2] {
+        match side {
+            Side::Left => [&self.left_1, &self.left_2],
+            Side::Right => [&self.right_1, &self.right_2],
+        }
+    }
+
+    // Input held high and input PWMed with inverted duty for a direction
+    fn high_pwm(&self, side: Side, direction: Direction) -> [&Pwm<'a>; 2] {
+        let [in1, in2] = self.inputs(side);
+        match direction {
+            Direction::Forward => [in1, in2],
+            Direction::Backwards => [in2, in1],
+        }
+    }
+}
+
+impl MotorDriver for Drv8833<'_> {
+    fn set(&mut self, side: Side, direction: Direction, duty: u8) -> Result<(), DriverError> {
+        let [high, pwm] = self.high_pwm(side, direction);
+        high.set_duty(100)?;
+        pwm.set_duty(100 - duty.min(100))?;
+        Ok(())
+    }
+
+    fn brake(&mut self, side: Side) -> Result<(), DriverError> {
+        for input in self.inputs(side) {
+            input.set_duty(100)?;
+        }
+        Ok(())
+    }
+
+    fn coast(&mut self, side: Side) -> Result<(), DriverError> {
+        for input in self.inputs(side) {
+            input.set_duty(0)?;
+        }
+        Ok(())
+    }
+}
+
+// TB6612FNG-like H-bridge: PWM input for speed, two direction inputs per motor
+// and a shared standby input, which may be tied high on the board instead
+pub struct Tb6612<'a> {
+    left_pwm: Pwm<'a>,
+    left_in1: Output<'a>,
+    left_in2: Output<'a>,
+    right_pwm: Pwm<'a>,
+    right_in1: Output<'a>,
+    right_in2: Output<'a>,
+    standby: Option<Output<'a>>,
+}
+
+impl<'a> Tb6612<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        left_pwm: Pwm<'a>,
+        left_in1: Output<'a>,
+        left_in2: Output<'a>,
+        right_pwm: Pwm<'a>,
+        right_in1: Output<'a>,
+        right_in2: Output<'a>,
+        mut standby: Option<Output<'a>>,
+    ) -> Result<Self, DriverError> {
+        left_pwm.set_duty(0)?;
+        right_pwm.set_duty(0)?;
+        // Standby is active low
+        if let Some(standby) = standby.as_mut() {
+            standby.set_high();
+        }
+        Ok(Self {
+            left_pwm,
+            left_in1,
+            left_in2,
+            right_pwm,
+            right_in1,
+            right_in2,
+            standby,
+        })
+    }
+
+    // Both motors coast while in standby, regardless of the other inputs.
+    // Returns false if standby is not wired
+    pub fn set_standby(&mut self, standby: bool) -> bool {
+        match self.standby.as_mut() {
+            Some(pin) => {
+                pin.set_level(Level::from(!standby));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_inputs(&mut self, side: Side, in1: Level, in2: Level) {
+        let (pin1, pin2) = match side {
+            Side::Left => (&mut self.left_in1, &mut self.left_in2),
+            Side::Right => (&mut self.right_in1, &mut self.right_in2),
+        };
+        pin1.set_level(in1);
+        pin2.set_level(in2);
+    }
+
+    fn set_direction(&mut self, side: Side, direction: Direction) {
+        match direction {
+            Direction::Forward => self.set_inputs(side, Level::High, Level::Low),
+            Direction::Backwards => self.set_inputs(side, Level::Low, Level::High),
+        }
+    }
+
+    fn pwm(&self, side: Side) -> &Pwm<'a> {
+        match side {
+            Side::Left => &self.left_pwm,
+            Side::Right => &self.right_pwm,
+        }
+    }
+}
+
+impl MotorDriver for Tb6612<'_> {
+    fn set(&mut self, side: Side, direction: Direction, duty: u8) -> Result<(), DriverError> {
+        self.set_direction(side, direction);
+        self.pwm(side).set_duty(duty)?;
+        Ok(())
+    }
+
+    fn brake(&mut self, side: Side) -> Result<(), DriverError> {
+        self.set_inputs(side, Level::High, Level::High);
+        Ok(())
+    }
+
+    fn coast(&mut self, side: Side) -> Result<(), DriverError> {
+        self.set_inputs(side, Level::Low, Level::Low);
+        Ok(())
+    }
+
+    // Standby doesn't depend on LEDC, so it works even if PWM doesn't
+    fn disable(&mut self) -> Result<(), DriverError> {
+        if !self.set_standby(true) {
+            self.coast(Side::Left)?;
+            self.coast(Side::Right)?;
+        }
+        Ok(())
+    }
+
+    fn enable(&mut self) -> Result<(), DriverError> {
+        self.set_standby(false);
+        Ok(())
+    }
+}
+
+// Driver with a single PWM input and a direction input per motor (e.g. DRV8838 in PH/EN mode).
+// Such drivers brake with PWM held low and have no separate coast state
+pub struct PwmDir<'a> {
+    left_pwm: Pwm<'a>,
+    left_dir: Output<'a>,
+    right_pwm: Pwm<'a>,
+    right_dir: Output<'a>,
+}
+
+impl<'a> PwmDir<'a> {
+    pub fn new(
+        left_pwm: Pwm<'a>,
+        left_dir: Output<'a>,
+        right_pwm: Pwm<'a>,
+        right_dir: Output<'a>,
+    ) -> Result<Self, DriverError> {
+        left_pwm.set_duty(0)?;
+        right_pwm.set_duty(0)?;
+        Ok(Self {
+            left_pwm,
+            left_dir,
+            right_pwm,
+            right_dir,
+        })
+    }
+
+    fn set_direction(&mut self, side: Side, direction: Direction) -> &Pwm<'a> {
+        let (pwm, dir) = match side {
+            Side::Left => (&self.left_pwm, &mut self.left_dir),
+            Side::Right => (&self.right_pwm, &mut self.right_dir),
+        };
+        dir.set_level(Level::from(direction == Direction::Forward));
+        pwm
+    }
+}
+
+impl MotorDriver for PwmDir<'_> {
+    fn set(&mut self, side: Side, direction: Direction, duty: u8) -> Result<(), DriverError> {
+        self.set_direction(side, direction).set_duty(duty)?;
+        Ok(())
+    }
+
+    fn brake(&mut self, side: Side) -> Result<(), DriverError> {
+        let pwm = match side {
+            Side::Left => &self.left_pwm,
+            Side::Right => &self.right_pwm,
+        };
+        pwm.set_duty(0)?;
+        Ok(())
+    }
+
+    fn coast(&mut self, side: Side) -> Result<(), DriverError> {
+        self.brake(side)
+    }
+}
@@ -0,0 +1,82 @@
+use heapless::Deque;
+
+use super::{Direction, DriverError, MotorDriver, Side};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DriverCall {
+    Set(Side, Direction, u8),
+    Brake(Side),
+    Coast(Side),
+    Disable,
+    Enable,
+}
+
+const MOCK_CALLS: usize = 64;
+
+// Records driver calls instead of driving anything, to check motor control logic on the host.
+// Only the latest MOCK_CALLS calls are kept
+#[derive(Default)]
+pub struct MockDriver {
+    calls: Deque<DriverCall, MOCK_CALLS>,
+    // Returned by every call while set, to check fault handling
+    fault: Option<DriverError>,
+}
+
+impl MockDriver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn calls(&self) -> impl Iterator<Item = &DriverCall> {
+        self.calls.iter()
+    }
+
+    pub fn last(&self, side: Side) -> Option<DriverCall> {
+        self.calls
+            .iter()
+            .rev()
+            .find(|c| match c {
+                DriverCall::Set(s, ..) | DriverCall::Brake(s) | DriverCall::Coast(s) => *s == side,
+                DriverCall::Disable | DriverCall::Enable => false,
+            })
+            .copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.calls.clear();
+    }
+
+    pub fn set_fault(&mut self, fault: Option<DriverError>) {
+        self.fault = fault;
+    }
+
+    fn record(&mut self, call: DriverCall) -> Result<(), DriverError> {
+        if self.calls.is_full() {
+            self.calls.pop_front();
+        }
+        self.calls.push_back(call).ok();
+        self.fault.map_or(Ok(()), Err)
+    }
+}
+
+impl MotorDriver for MockDriver {
+    fn set(&mut self, side: Side, direction: Direction, duty: u8) -> Result<(), DriverError> {
+        self.record(DriverCall::Set(side, direction, duty))
+    }
+
+    fn brake(&mut self, side: Side) -> Result<(), DriverError> {
+        self.record(DriverCall::Brake(side))
+    }
+
+    fn coast(&mut self, side: Side) -> Result<(), DriverError> {
+        self.record(DriverCall::Coast(side))
+    }
+
+    fn disable(&mut self) -> Result<(), DriverError> {
+        self.record(DriverCall::Disable)
+    }
+
+    fn enable(&mut self) -> Result<(), DriverError> {
+        self.record(DriverCall::Enable)
+    }
+}
@@ -1,16 +1,24 @@
+#[cfg(target_arch = "riscv32")]
 use core::cell::RefCell;
 
+#[cfg(target_arch = "riscv32")]
 use critical_section::Mutex;
+#[cfg(target_arch = "riscv32")]
 use esp_hal::gpio::{AnyPin, Event, Input, InputConfig, Io, Pull};
+#[cfg(target_arch = "riscv32")]
 use esp_hal::peripherals;
-use portable_atomic::{AtomicU32, Ordering};
+use portable_atomic::AtomicU32;
+#[cfg(target_arch = "riscv32")]
+use portable_atomic::Ordering;
 
 pub static MOTOR1_PULSES: AtomicU32 = AtomicU32::new(0);
 pub static MOTOR2_PULSES: AtomicU32 = AtomicU32::new(0);
 
+#[cfg(target_arch = "riscv32")]
 static ENC_PINS: Mutex<RefCell<Option<(Input<'static>, Input<'static>)>>> =
     Mutex::new(RefCell::new(None));
 
+#[cfg(target_arch = "riscv32")]
 #[esp_hal::handler]
 fn gpio_interrupt() {
     critical_section::with(|cs| {
@@ -29,6 +37,7 @@ fn gpio_interrupt() {
     });
 }
 
+#[cfg(target_arch = "riscv32")]
 pub fn init(
     io_mux: peripherals::IO_MUX<'static>,
     mot1_enc: AnyPin<'static>,
@@ -1,11 +1,21 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(feature = "pid")]
+pub mod autotune;
+pub mod calibration;
 pub mod color;
 pub mod comm;
 pub mod control;
+#[cfg(target_arch = "riscv32")]
 pub mod distance;
+pub mod driver;
 pub mod encoder;
 pub mod motors;
 #[cfg(feature = "pid")]
 pub mod pid;
+pub mod profile;
+pub mod program;
+pub mod remote;
+pub mod storage;
+pub mod surface;
 pub mod telemetry;
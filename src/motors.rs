@@ -1,25 +1,106 @@
+#[cfg(feature = "pid")]
+use crate::autotune::{AutoTune, Relay, UltimateGain};
+use crate::calibration::{MotorCalibration, Sweep};
+use crate::driver::{Direction, DriverError, MotorDriver, Side};
 use crate::encoder::{MOTOR1_PULSES, MOTOR2_PULSES};
 use crate::pid::Pid;
-use esp_hal::ledc::{self, channel::ChannelIFace};
+use crate::profile::{Profile, ProfileKind, Ramp};
+use crate::storage::{Reader, StorageError, Store, Writer};
+use crate::surface::Surface;
+use embassy_time::Instant;
+use heapless::Deque;
 use portable_atomic::Ordering;
 
 const ACCEL_TIME: u16 = 200; // ms
-const DECEL_TIME_L: u16 = 100;
-const DECEL_TIME_R: u16 = 100;
+const DECEL_TIME: u16 = 100;
+// How often duty ramps are updated, encoder counts are checked and wheel speed is adjusted
+// during a move
+const CONTROL_PERIOD: u64 = 20; // ms
 
+// Battery readings below this mean the robot runs off USB, duty is not compensated then
+const MIN_BATTERY_VOLTAGE: u16 = 2000; // mV
+// Limits of the battery compensation, in 1/1000
+const MIN_COMPENSATION: u32 = 800;
+const MAX_COMPENSATION: u32 = 1500;
+
+// Calibration runs each duty for the settle time before measuring speed over the measure time.
+// When it switches to another wheel, it waits longer for the previous one to spin down
+const CALIBRATION_SETTLE: u64 = 300; // ms
+const CALIBRATION_MEASURE: u64 = 500; // ms
+const CALIBRATION_PAUSE: u64 = 1000; // ms
+
+// Auto-tune switches the duty this much above and below the open-loop duty of the nominal speed
+#[cfg(feature = "pid")]
+const AUTOTUNE_AMPLITUDE: i32 = 15; // %
+#[cfg(feature = "pid")]
+const AUTOTUNE_HYSTERESIS: i32 = 5; // pulses/s
+// Encoders count only a few pulses per control period, so speed is measured less often
+#[cfg(feature = "pid")]
+const AUTOTUNE_PERIOD: u64 = 50; // ms
+// Gives up on a wheel that doesn't oscillate
+#[cfg(feature = "pid")]
+const AUTOTUNE_TIMEOUT: u64 = 10_000; // ms
+
+// Wheel speed is compared with the calibrated curve over this window. A wheel this much
+// faster than expected is slipping, this much slower is dragged
+const TRACTION_WINDOW: u64 = 100; // ms
+const TRACTION_MARGIN: u32 = 30; // %
+// Encoder speed is too coarse below this
+const TRACTION_MIN_SPEED: u32 = 10; // pulses/s
+// Slip doubles the acceleration time of the move up to this
+const MAX_SLIP_ACCEL_TIME: u16 = 1000; // ms
+
+// Layout of the stored config, bump when it changes
+const CONFIG_VERSION: u16 = 4;
+const CONFIG_SIZE: usize = 256;
+
+// Commands accepted while a move is in progress
+pub const QUEUE_SIZE: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StopMode {
+    // Duty ramps down along the profile and the motors spin down freely
+    Coast,
+    // Motors are shorted for the brake hold time, then released
+    Brake,
+}
+
+#[derive(Debug, Copy, Clone)]
 pub struct Config {
-    accel_time: u16,
-    decel_time_l: u16,
-    decel_time_r: u16,
+    // Straight moves and arcs
+    drive_profile: Profile,
+    turn_profile: Profile,
     left_duty: u8,
     right_duty: u8,
+    wheel_circumference_mm: u32,
+    pulses_per_rev: u32,
+    // Distance between the wheels
+    track_width_mm: u32,
+    // How moves end once their target is reached
+    stop_mode: StopMode,
+    emergency_stop_mode: StopMode,
+    brake_hold_time: u16, // ms
+    // Pulses still counted while decelerating, stop this much before the target
+    decel_pulses: u32,
+    // Give up on a distance-based move if encoders don't report for this long
+    stall_timeout: u64,
+    // Battery voltage the duties are tuned at. Duty is scaled up as the battery drains
+    nominal_voltage: u16, // mV
+    // Wheel speed at 100% speed, held by the speed control loop
+    target_speed: u32, // pulses/s
+    // Wheel speed at 100% speed while turning in place
+    turn_speed: u32, // pulses/s
+    // Extra time for timed moves with a reversing wheel, which first takes up the gearbox
+    // backlash. Learned from how much later reversing wheels start counting pulses.
+    // Encoders count the wheels after the backlash, so encoder-based moves need nothing extra
+    backlash_time: u16, // ms
+    // Measured duty -> speed curves. When present, they replace left_duty/right_duty
+    calibration: Option<MotorCalibration>,
     #[cfg(feature = "pid")]
     min_duty: u8,
     #[cfg(feature = "pid")]
     max_duty: u8,
     #[cfg(feature = "pid")]
-    target_forward: u32,
-    #[cfg(feature = "pid")]
     pid_kp: i32,
     #[cfg(feature = "pid")]
     pid_ki: i32,
@@ -27,176 +108,650 @@ pub struct Config {
     pid_kd: i32,
     #[cfg(feature = "pid")]
     pid_integral_limit: i32,
+    // Cross-coupled controller keeping left and right pulse counts equal
+    #[cfg(feature = "pid")]
+    sync_kp: i32,
+    #[cfg(feature = "pid")]
+    sync_ki: i32,
+    #[cfg(feature = "pid")]
+    sync_kd: i32,
+    #[cfg(feature = "pid")]
+    sync_integral_limit: i32,
+    // Spinning in place loads the motors differently, so turns have their own speed controller
+    #[cfg(feature = "pid")]
+    turn_kp: i32,
+    #[cfg(feature = "pid")]
+    turn_ki: i32,
+    #[cfg(feature = "pid")]
+    turn_kd: i32,
+    #[cfg(feature = "pid")]
+    turn_integral_limit: i32,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            accel_time: ACCEL_TIME,
-            decel_time_l: DECEL_TIME_L,
-            decel_time_r: DECEL_TIME_R,
+            // Soft start keeps the wheels from slipping on the mats
+            drive_profile: Profile {
+                kind: ProfileKind::SCurve,
+                accel_time: ACCEL_TIME,
+                decel_time: DECEL_TIME,
+            },
+            turn_profile: Profile {
+                kind: ProfileKind::Trapezoidal,
+                accel_time: ACCEL_TIME,
+                decel_time: DECEL_TIME,
+            },
             left_duty: 70,
             right_duty: 70,
+            wheel_circumference_mm: 100,
+            pulses_per_rev: 40,
+            track_width_mm: 80,
+            stop_mode: StopMode::Coast,
+            emergency_stop_mode: StopMode::Brake,
+            brake_hold_time: 200,
+            decel_pulses: 4,
+            stall_timeout: 300,
+            nominal_voltage: 3900,
+            target_speed: 80,
+            turn_speed: 60,
+            backlash_time: 0,
+            calibration: None,
             #[cfg(feature = "pid")]
             min_duty: 70,
             #[cfg(feature = "pid")]
             max_duty: 80,
             #[cfg(feature = "pid")]
-            target_forward: 40,
+            pid_kp: 8,
+            #[cfg(feature = "pid")]
+            pid_ki: 2,
+            #[cfg(feature = "pid")]
+            pid_kd: 0,
+            #[cfg(feature = "pid")]
+            pid_integral_limit: 2048,
+            #[cfg(feature = "pid")]
+            sync_kp: 256,
+            #[cfg(feature = "pid")]
+            sync_ki: 16,
+            #[cfg(feature = "pid")]
+            sync_kd: 64,
+            #[cfg(feature = "pid")]
+            sync_integral_limit: 256,
+            #[cfg(feature = "pid")]
+            turn_kp: 8,
+            #[cfg(feature = "pid")]
+            turn_ki: 2,
+            #[cfg(feature = "pid")]
+            turn_kd: 0,
+            #[cfg(feature = "pid")]
+            turn_integral_limit: 2048,
+        }
+    }
+}
+
+fn write_profile(w: &mut Writer, profile: &Profile) {
+    w.u8(match profile.kind {
+        ProfileKind::Trapezoidal => 0,
+        ProfileKind::SCurve => 1,
+    });
+    w.u16(profile.accel_time);
+    w.u16(profile.decel_time);
+}
+
+fn read_profile(r: &mut Reader) -> Option<Profile> {
+    let kind = match r.u8()? {
+        0 => ProfileKind::Trapezoidal,
+        1 => ProfileKind::SCurve,
+        _ => return None,
+    };
+    Some(Profile {
+        kind,
+        accel_time: r.u16()?,
+        decel_time: r.u16()?,
+    })
+}
+
+fn write_stop_mode(w: &mut Writer, mode: StopMode) {
+    w.u8((mode == StopMode::Brake) as u8);
+}
+
+fn read_stop_mode(r: &mut Reader) -> Option<StopMode> {
+    match r.u8()? {
+        0 => Some(StopMode::Coast),
+        1 => Some(StopMode::Brake),
+        _ => None,
+    }
+}
+
+impl Config {
+    // Starting point for a surface, before calibration and learning
+    pub fn for_surface(surface: Surface) -> Self {
+        let config = Self::default();
+        match surface {
+            Surface::Table => config,
+            // Carpet holds the wheels back, they need more duty and longer to stop counting
+            Surface::Carpet => Self {
+                left_duty: 85,
+                right_duty: 85,
+                stall_timeout: 500,
+                #[cfg(feature = "pid")]
+                min_duty: 80,
+                #[cfg(feature = "pid")]
+                max_duty: 95,
+                ..config
+            },
+            // Foam mats are soft and slippery, so acceleration is gentler
+            Surface::Foam => Self {
+                drive_profile: Profile {
+                    kind: ProfileKind::SCurve,
+                    accel_time: 2 * ACCEL_TIME,
+                    decel_time: 2 * DECEL_TIME,
+                },
+                turn_profile: Profile {
+                    kind: ProfileKind::SCurve,
+                    accel_time: 2 * ACCEL_TIME,
+                    decel_time: 2 * DECEL_TIME,
+                },
+                left_duty: 75,
+                right_duty: 75,
+                #[cfg(feature = "pid")]
+                min_duty: 75,
+                #[cfg(feature = "pid")]
+                max_duty: 85,
+                ..config
+            },
+        }
+    }
+
+    // PID fields go last, so a build without the pid feature can still read a config
+    // saved with it
+    fn write(&self, w: &mut Writer) {
+        write_profile(w, &self.drive_profile);
+        write_profile(w, &self.turn_profile);
+        w.u8(self.left_duty);
+        w.u8(self.right_duty);
+        w.u32(self.wheel_circumference_mm);
+        w.u32(self.pulses_per_rev);
+        w.u32(self.track_width_mm);
+        write_stop_mode(w, self.stop_mode);
+        write_stop_mode(w, self.emergency_stop_mode);
+        w.u16(self.brake_hold_time);
+        w.u32(self.decel_pulses);
+        w.u64(self.stall_timeout);
+        w.u16(self.nominal_voltage);
+        w.u32(self.target_speed);
+        w.u32(self.turn_speed);
+        w.u16(self.backlash_time);
+        match &self.calibration {
+            Some(calibration) => {
+                w.u8(1);
+                calibration.write(w);
+            }
+            None => w.u8(0),
+        }
+        #[cfg(feature = "pid")]
+        {
+            w.u8(self.min_duty);
+            w.u8(self.max_duty);
+            w.i32(self.pid_kp);
+            w.i32(self.pid_ki);
+            w.i32(self.pid_kd);
+            w.i32(self.pid_integral_limit);
+            w.i32(self.sync_kp);
+            w.i32(self.sync_ki);
+            w.i32(self.sync_kd);
+            w.i32(self.sync_integral_limit);
+            w.i32(self.turn_kp);
+            w.i32(self.turn_ki);
+            w.i32(self.turn_kd);
+            w.i32(self.turn_integral_limit);
+        }
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            drive_profile: read_profile(r)?,
+            turn_profile: read_profile(r)?,
+            left_duty: r.u8()?,
+            right_duty: r.u8()?,
+            wheel_circumference_mm: r.u32()?,
+            pulses_per_rev: r.u32()?,
+            track_width_mm: r.u32()?,
+            stop_mode: read_stop_mode(r)?,
+            emergency_stop_mode: read_stop_mode(r)?,
+            brake_hold_time: r.u16()?,
+            decel_pulses: r.u32()?,
+            stall_timeout: r.u64()?,
+            nominal_voltage: r.u16()?,
+            target_speed: r.u32()?,
+            turn_speed: r.u32()?,
+            backlash_time: r.u16()?,
+            calibration: match r.u8()? {
+                0 => None,
+                _ => Some(MotorCalibration::read(r)?),
+            },
+            #[cfg(feature = "pid")]
+            min_duty: r.u8()?,
+            #[cfg(feature = "pid")]
+            max_duty: r.u8()?,
+            #[cfg(feature = "pid")]
+            pid_kp: r.i32()?,
+            #[cfg(feature = "pid")]
+            pid_ki: r.i32()?,
+            #[cfg(feature = "pid")]
+            pid_kd: r.i32()?,
+            #[cfg(feature = "pid")]
+            pid_integral_limit: r.i32()?,
+            #[cfg(feature = "pid")]
+            sync_kp: r.i32()?,
+            #[cfg(feature = "pid")]
+            sync_ki: r.i32()?,
+            #[cfg(feature = "pid")]
+            sync_kd: r.i32()?,
             #[cfg(feature = "pid")]
-            pid_kp: 32,
+            sync_integral_limit: r.i32()?,
             #[cfg(feature = "pid")]
-            pid_ki: 4,
+            turn_kp: r.i32()?,
             #[cfg(feature = "pid")]
-            pid_kd: 8,
+            turn_ki: r.i32()?,
             #[cfg(feature = "pid")]
-            pid_integral_limit: 512,
+            turn_kd: r.i32()?,
+            #[cfg(feature = "pid")]
+            turn_integral_limit: r.i32()?,
+        })
+    }
+
+    // Config of a surface saved by save(), if there is a valid one
+    pub fn load(store: &mut Store, surface: Surface) -> Result<Self, StorageError> {
+        let mut buf = [0u8; CONFIG_SIZE];
+        let data = store.load(surface.slot(), CONFIG_VERSION, &mut buf)?;
+        Self::read(&mut Reader::new(data)).ok_or(StorageError::Corrupted)
+    }
+
+    pub fn save(&self, store: &mut Store, surface: Surface) -> Result<(), StorageError> {
+        let mut buf = [0u8; CONFIG_SIZE];
+        let mut w = Writer::new(&mut buf);
+        self.write(&mut w);
+        store.save(surface.slot(), CONFIG_VERSION, w.data()?)
+    }
+
+    fn mm_to_pulses(&self, mm: u32) -> u32 {
+        mm * self.pulses_per_rev / self.wheel_circumference_mm
+    }
+
+    // Turning in place, each wheel travels along a circle of track width diameter.
+    // pi is approximated as 355/113
+    fn deg_to_pulses(&self, deg: u16) -> u32 {
+        (self.track_width_mm as u64 * deg as u64 * 355 * self.pulses_per_rev as u64
+            / (113 * 360 * self.wheel_circumference_mm as u64)) as u32
+    }
+
+    // Radii of the circles the wheels travel along on an arc, as (left, right).
+    // Radius is limited to half the track, so the inner wheel stops instead of reversing
+    fn arc_radii(&self, radius_mm: i32) -> (u32, u32) {
+        let half_track = self.track_width_mm / 2;
+        let radius = radius_mm.unsigned_abs().max(half_track);
+        if radius_mm > 0 {
+            (radius - half_track, radius + half_track)
+        } else {
+            (radius + half_track, radius - half_track)
+        }
+    }
+
+    // Duty for a wheel running at `speed` percent of the nominal speed
+    fn duty(&self, duty: u8, speed: u8) -> u8 {
+        (duty as u32 * speed as u32 / 100).min(100) as u8
+    }
+
+    // Open-loop duty for a wheel running at `speed` percent of the nominal speed.
+    // Taken from the calibrated speed curve if there is one
+    fn base_duty(&self, side: Side, direction: Direction, speed: u8, turn: bool) -> u8 {
+        let nominal_speed = if turn {
+            self.turn_speed
+        } else {
+            self.target_speed
+        };
+        match &self.calibration {
+            Some(calibration) => calibration
+                .curve(side, direction)
+                .duty(nominal_speed * speed as u32 / 100),
+            None => match side {
+                Side::Left => self.duty(self.left_duty, speed),
+                Side::Right => self.duty(self.right_duty, speed),
+            },
+        }
+    }
+
+    // Range the speed control loop may move the duty in
+    #[cfg(feature = "pid")]
+    fn duty_limits(&self, side: Side, direction: Direction, speed: u8) -> (u8, u8) {
+        match &self.calibration {
+            Some(calibration) => (calibration.curve(side, direction).dead_band, 100),
+            None => (
+                self.duty(self.min_duty, speed),
+                self.duty(self.max_duty, speed),
+            ),
+        }
+    }
+
+    fn pulses_to_deg(&self, pulses: u32) -> u16 {
+        (pulses as u64 * 113 * 360 * self.wheel_circumference_mm as u64
+            / (self.track_width_mm as u64 * 355 * self.pulses_per_rev as u64)) as u16
+    }
+}
+
+struct Wheel {
+    direction: Direction,
+    duty: u8,
+    ramp: Option<Ramp>,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Self {
+            direction: Direction::Forward,
+            duty: 0,
+            ramp: None,
+        }
+    }
+
+    // Running or about to start running, and not already ramping down
+    fn needs_stop(&self) -> bool {
+        match self.ramp {
+            Some(ramp) => ramp.target() > 0,
+            None => self.duty > 0,
         }
     }
 }
 
-pub struct Motors<'a> {
-    left_1: ledc::channel::Channel<'a, ledc::LowSpeed>,
-    left_2: ledc::channel::Channel<'a, ledc::LowSpeed>,
-    right_1: ledc::channel::Channel<'a, ledc::LowSpeed>,
-    right_2: ledc::channel::Channel<'a, ledc::LowSpeed>,
+pub struct Motors<D: MotorDriver> {
+    driver: D,
     config: Config,
-    l1: u8,
-    l2: u8,
-    r1: u8,
-    r2: u8,
+    profile: Profile,
+    left: Wheel,
+    right: Wheel,
+    // Battery feed-forward, in 1/1000. Applied to the duty written to the driver only,
+    // so ramps and speed control work with duties at the nominal voltage
+    compensation: u32,
 }
 
-impl<'a> Motors<'a> {
-    pub fn init(
-        left_1: ledc::channel::Channel<'a, ledc::LowSpeed>,
-        left_2: ledc::channel::Channel<'a, ledc::LowSpeed>,
-        right_1: ledc::channel::Channel<'a, ledc::LowSpeed>,
-        right_2: ledc::channel::Channel<'a, ledc::LowSpeed>,
-        config: Config,
-    ) -> Self {
-        left_1.set_duty(0).unwrap();
-        left_2.set_duty(0).unwrap();
-        right_1.set_duty(0).unwrap();
-        right_2.set_duty(0).unwrap();
+impl<D: MotorDriver> Motors<D> {
+    pub fn init(driver: D, config: Config) -> Self {
         Self {
-            left_1,
-            left_2,
-            right_1,
-            right_2,
+            driver,
+            profile: config.drive_profile,
             config,
-            l1: 0,
-            l2: 0,
-            r1: 0,
-            r2: 0,
+            left: Wheel::new(),
+            right: Wheel::new(),
+            compensation: 1000,
         }
     }
 
-    pub fn forward(&mut self) -> u16 {
-        self.left_1
-            .start_duty_fade(0, self.config.left_duty, self.config.accel_time)
-            .unwrap();
-        self.left_2.set_duty(0).unwrap();
-        self.right_1
-            .start_duty_fade(0, self.config.right_duty, self.config.accel_time)
-            .unwrap();
-        self.right_2.set_duty(0).unwrap();
+    fn compensate(&self, duty: u8) -> u8 {
+        (duty as u32 * self.compensation / 1000).min(100) as u8
+    }
 
-        self.l1 = self.config.left_duty;
-        self.l2 = 0;
-        self.r1 = self.config.right_duty;
-        self.r2 = 0;
+    fn write_left(&mut self, duty: u8) -> Result<(), DriverError> {
+        let output = self.compensate(duty);
+        self.driver.set(Side::Left, self.left.direction, output)?;
+        self.left.duty = duty;
+        Ok(())
+    }
 
-        self.config.accel_time
+    fn write_right(&mut self, duty: u8) -> Result<(), DriverError> {
+        let output = self.compensate(duty);
+        self.driver.set(Side::Right, self.right.direction, output)?;
+        self.right.duty = duty;
+        Ok(())
     }
 
-    pub fn backwards(&mut self) -> u16 {
-        self.left_2
-            .start_duty_fade(0, self.config.left_duty, self.config.accel_time)
-            .unwrap();
-        self.left_1.set_duty(0).unwrap();
-        self.right_2
-            .start_duty_fade(0, self.config.right_duty, self.config.accel_time)
-            .unwrap();
-        self.right_1.set_duty(0).unwrap();
+    // Scales duty by nominal/measured voltage, so moves don't shrink as the battery drains
+    pub fn set_voltage(&mut self, mv: u16) -> Result<(), DriverError> {
+        self.compensation = if mv < MIN_BATTERY_VOLTAGE {
+            1000
+        } else {
+            (self.config.nominal_voltage as u32 * 1000 / mv as u32)
+                .clamp(MIN_COMPENSATION, MAX_COMPENSATION)
+        };
+        // Running wheels pick up the new compensation right away
+        if self.left.duty > 0 {
+            self.write_left(self.left.duty)?;
+        }
+        if self.right.duty > 0 {
+            self.write_right(self.right.duty)?;
+        }
+        Ok(())
+    }
+
+    pub fn compensation(&self) -> u16 {
+        self.compensation as u16
+    }
 
-        self.l2 = self.config.left_duty;
-        self.l1 = 0;
-        self.r2 = self.config.right_duty;
-        self.r1 = 0;
+    // Open-loop duty of a wheel for its current direction
+    fn base_duty(&self, side: Side, speed: u8) -> u8 {
+        let direction = match side {
+            Side::Left => self.left.direction,
+            Side::Right => self.right.direction,
+        };
+        let turn = self.left.direction != self.right.direction;
+        self.config.base_duty(side, direction, speed, turn)
+    }
 
-        self.config.accel_time
+    fn ramp_time(&self, ramp: &Ramp) -> u16 {
+        if ramp.accelerating() {
+            self.profile.accel_time
+        } else {
+            self.profile.decel_time
+        }
     }
 
-    pub fn right(&mut self) -> u16 {
-        self.left_1
-            .start_duty_fade(0, self.config.left_duty, self.config.accel_time)
-            .unwrap();
-        self.left_2.set_duty(0).unwrap();
-        self.right_2
-            .start_duty_fade(0, self.config.right_duty, self.config.accel_time)
-            .unwrap();
-        self.right_1.set_duty(0).unwrap();
+    fn drive(
+        &mut self,
+        left_direction: Direction,
+        right_direction: Direction,
+        left_speed: u8,
+        right_speed: u8,
+    ) -> Result<u16, DriverError> {
+        self.left.direction = left_direction;
+        self.right.direction = right_direction;
+        let left_duty = self.base_duty(Side::Left, left_speed);
+        let right_duty = self.base_duty(Side::Right, right_speed);
+        let kind = self.profile.kind;
+        let time = self.profile.accel_time;
 
-        self.l1 = self.config.left_duty;
-        self.l2 = 0;
-        self.r2 = self.config.right_duty;
-        self.r1 = 0;
+        self.left.ramp = Some(Ramp::new(kind, 0, left_duty, time));
+        self.right.ramp = Some(Ramp::new(kind, 0, right_duty, time));
+        self.write_left(0)?;
+        self.write_right(0)?;
 
-        self.config.accel_time
+        Ok(time)
     }
 
-    pub fn left(&mut self) -> u16 {
-        self.left_2
-            .start_duty_fade(0, self.config.left_duty, self.config.accel_time)
-            .unwrap();
-        self.left_1.set_duty(0).unwrap();
-        self.right_1
-            .start_duty_fade(0, self.config.right_duty, self.config.accel_time)
-            .unwrap();
-        self.right_2.set_duty(0).unwrap();
+    pub fn forward(&mut self, left_speed: u8, right_speed: u8) -> Result<u16, DriverError> {
+        self.drive(
+            Direction::Forward,
+            Direction::Forward,
+            left_speed,
+            right_speed,
+        )
+    }
 
-        self.l2 = self.config.left_duty;
-        self.l1 = 0;
-        self.r1 = self.config.right_duty;
-        self.r2 = 0;
+    pub fn backwards(&mut self, left_speed: u8, right_speed: u8) -> Result<u16, DriverError> {
+        self.drive(
+            Direction::Backwards,
+            Direction::Backwards,
+            left_speed,
+            right_speed,
+        )
+    }
 
-        self.config.accel_time
+    pub fn right(&mut self, left_speed: u8, right_speed: u8) -> Result<u16, DriverError> {
+        self.drive(
+            Direction::Forward,
+            Direction::Backwards,
+            left_speed,
+            right_speed,
+        )
     }
 
-    pub fn stop(&mut self) -> u16 {
-        if self.l1 > 0 {
-            self.left_1
-                .start_duty_fade(self.l1, 0, self.config.decel_time_l)
-                .unwrap();
+    pub fn left(&mut self, left_speed: u8, right_speed: u8) -> Result<u16, DriverError> {
+        self.drive(
+            Direction::Backwards,
+            Direction::Forward,
+            left_speed,
+            right_speed,
+        )
+    }
+
+    // Runs a wheel at a fixed duty right away, without a ramp
+    pub fn run_wheel(
+        &mut self,
+        side: Side,
+        direction: Direction,
+        duty: u8,
+    ) -> Result<(), DriverError> {
+        match side {
+            Side::Left => {
+                self.left.ramp = None;
+                self.left.direction = direction;
+                self.write_left(duty)
+            }
+            Side::Right => {
+                self.right.ramp = None;
+                self.right.direction = direction;
+                self.write_right(duty)
+            }
+        }
+    }
+
+    // Moves duty ramps along the profile. Has to be called every control period.
+    // Returns true while any wheel is still ramping
+    pub fn update(&mut self) -> Result<bool, DriverError> {
+        if let Some(ramp) = self.left.ramp {
+            let (duty, done) = ramp.duty();
+            self.write_left(duty)?;
+            if done {
+                self.left.ramp = None;
+            }
+        }
+        if let Some(ramp) = self.right.ramp {
+            let (duty, done) = ramp.duty();
+            self.write_right(duty)?;
+            if done {
+                self.right.ramp = None;
+            }
+        }
+        Ok(self.left.ramp.is_some() || self.right.ramp.is_some())
+    }
+
+    // Switches profile, ramps in progress continue from the current duty with the new timing
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.profile = profile;
+        if let Some(ramp) = self.left.ramp {
+            let time = self.ramp_time(&ramp);
+            self.left.ramp = Some(Ramp::new(profile.kind, self.left.duty, ramp.target(), time));
+        }
+        if let Some(ramp) = self.right.ramp {
+            let time = self.ramp_time(&ramp);
+            self.right.ramp = Some(Ramp::new(
+                profile.kind,
+                self.right.duty,
+                ramp.target(),
+                time,
+            ));
+        }
+    }
+
+    // Changes duty of the running wheels that are not ramping
+    pub fn set_duty(&mut self, left: u8, right: u8) -> Result<(), DriverError> {
+        if self.left.duty > 0 && self.left.ramp.is_none() {
+            self.write_left(left)?;
         }
-        if self.l2 > 0 {
-            self.left_2
-                .start_duty_fade(self.l2, 0, self.config.decel_time_l)
-                .unwrap();
+        if self.right.duty > 0 && self.right.ramp.is_none() {
+            self.write_right(right)?;
         }
-        if self.r1 > 0 {
-            self.right_1
-                .start_duty_fade(self.r1, 0, self.config.decel_time_r)
-                .unwrap();
+        Ok(())
+    }
+
+    pub fn stop_left(&mut self) -> u16 {
+        if self.left.needs_stop() {
+            self.left.ramp = Some(Ramp::new(
+                self.profile.kind,
+                self.left.duty,
+                0,
+                self.profile.decel_time,
+            ));
         }
-        if self.r2 > 0 {
-            self.right_2
-                .start_duty_fade(self.r2, 0, self.config.decel_time_r)
-                .unwrap();
+
+        self.profile.decel_time
+    }
+
+    pub fn stop_right(&mut self) -> u16 {
+        if self.right.needs_stop() {
+            self.right.ramp = Some(Ramp::new(
+                self.profile.kind,
+                self.right.duty,
+                0,
+                self.profile.decel_time,
+            ));
         }
 
-        core::cmp::max(self.config.decel_time_l, self.config.decel_time_r)
+        self.profile.decel_time
+    }
+
+    pub fn stop(&mut self) -> u16 {
+        core::cmp::max(self.stop_left(), self.stop_right())
+    }
+
+    pub fn brake_left(&mut self) -> Result<(), DriverError> {
+        self.left.ramp = None;
+        self.left.duty = 0;
+        self.driver.brake(Side::Left)
+    }
+
+    pub fn brake_right(&mut self) -> Result<(), DriverError> {
+        self.right.ramp = None;
+        self.right.duty = 0;
+        self.driver.brake(Side::Right)
+    }
+
+    // Shorts both motors, so they stop much quicker than with stop().
+    // Returns how long the brake should be held
+    pub fn brake(&mut self) -> Result<u16, DriverError> {
+        self.brake_left()?;
+        self.brake_right()?;
+
+        Ok(self.config.brake_hold_time)
+    }
+
+    // Releases both motors
+    pub fn coast(&mut self) -> Result<(), DriverError> {
+        self.left.ramp = None;
+        self.right.ramp = None;
+        self.left.duty = 0;
+        self.right.duty = 0;
+        self.driver.coast(Side::Left)?;
+        self.driver.coast(Side::Right)
     }
 
     // No decelleration
-    pub fn emergency_stop(&mut self) -> u16 {
-        self.left_1.set_duty(0).unwrap();
-        self.left_2.set_duty(0).unwrap();
-        self.right_1.set_duty(0).unwrap();
-        self.right_2.set_duty(0).unwrap();
+    pub fn emergency_stop(&mut self) -> Result<u16, DriverError> {
+        self.coast()?;
 
-        0
+        Ok(0)
+    }
+
+    // All outputs off after a driver fault, as far as the driver can still do it
+    pub fn disable(&mut self) -> Result<(), DriverError> {
+        self.left.ramp = None;
+        self.right.ramp = None;
+        self.left.duty = 0;
+        self.right.duty = 0;
+        self.driver.disable()
+    }
+
+    pub fn enable(&mut self) -> Result<(), DriverError> {
+        self.driver.enable()
     }
 }
 
@@ -205,11 +760,63 @@ pub enum MotorsSmCommand {
     Forward(u64),
     #[allow(dead_code)]
     Backwards(u64),
-    #[allow(dead_code)]
-    Stop,
+    // Distance in mm, measured with encoders, and speed in percent of the nominal speed
+    ForwardDistance(u32, u8),
+    BackwardsDistance(u32, u8),
+    // Cancels the current move, the rest of it is reported by last_cancelled()
+    Stop(StopMode),
     EmergencyStop,
+    // Stops the current move and holds the queue until Resume, which finishes the move
+    Pause,
+    Resume,
+    // Stands still for the given time, in ms, then reports a Complete event like a move
+    Wait(u64),
+    // Sweeps duty of each wheel in each direction to measure its speed curve.
+    // The robot spins in place while calibrating
+    Calibrate,
+    // Relay experiment on each wheel to find the speed controller gains.
+    // The robot spins in place while tuning
+    #[cfg(feature = "pid")]
+    AutoTune,
     Left(u64),
     Right(u64),
+    // Angle in degrees, measured with encoders, and speed in percent of the nominal speed
+    TurnLeft(u16, u8),
+    TurnRight(u16, u8),
+    // Drive forward along a circle, positive radius curves to the left.
+    // Length is measured along the robot center, speed is of the outer wheel
+    Arc {
+        radius_mm: i32,
+        length_mm: u32,
+        speed: u8,
+    },
+}
+
+// Turning in place loads the motors differently from driving
+fn turn_cmd(cmd: MotorsSmCommand) -> bool {
+    matches!(
+        cmd,
+        MotorsSmCommand::Left(_)
+            | MotorsSmCommand::Right(_)
+            | MotorsSmCommand::TurnLeft(..)
+            | MotorsSmCommand::TurnRight(..)
+    )
+}
+
+// Directions of the left and right wheel during a move
+fn cmd_directions(cmd: MotorsSmCommand) -> (Direction, Direction) {
+    match cmd {
+        MotorsSmCommand::Backwards(_) | MotorsSmCommand::BackwardsDistance(..) => {
+            (Direction::Backwards, Direction::Backwards)
+        }
+        MotorsSmCommand::Left(_) | MotorsSmCommand::TurnLeft(..) => {
+            (Direction::Backwards, Direction::Forward)
+        }
+        MotorsSmCommand::Right(_) | MotorsSmCommand::TurnRight(..) => {
+            (Direction::Forward, Direction::Backwards)
+        }
+        _ => (Direction::Forward, Direction::Forward),
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -221,45 +828,216 @@ enum MotorSmState {
     Backwards,
     Left,
     Right,
+    Arc,
+    // Emergency stop, motors are shorted for the brake hold time
+    Braking,
+    // Waiting for Resume, queued commands are held
+    Paused,
+    Calibrating,
+    #[cfg(feature = "pid")]
+    AutoTuning,
+    // Wait command, motors are off
+    Waiting,
+    // Driver error, outputs are off until clear_fault()
+    Faulted,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MotionOutcome {
+    Complete,
+    Cancelled,
+    // Stopped by Pause, the rest of the move runs on Resume and reports its own event.
+    // A move paused while it was finishing anyway reports Complete on Resume instead
+    Paused,
+    // Emergency stop
+    Aborted,
+}
+
+// Reported once a move has ended
+#[derive(Debug, Copy, Clone)]
+pub struct MotionEvent {
+    pub outcome: MotionOutcome,
+    pub cmd: MotorsSmCommand,
+    pub left_pulses: u32,
+    pub right_pulses: u32,
+    pub duration: u64, // ms
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Traction {
+    // Wheel spins faster than the duty should make it, e.g. on a slick mat
+    Slip,
+    // Wheel is slower than expected, e.g. on carpet or pushing against something
+    Drag,
+}
+
+// Request to end the current move early
+#[derive(Debug, Copy, Clone)]
+enum Interrupt {
+    Cancel(StopMode),
+    Pause,
 }
 
 #[derive(Debug, Copy, Clone)]
 pub enum MotorsSmError {
+    QueueFull,
+    NoSuchCommand,
+    NotPaused,
+    // Only possible while stopped
     Busy,
+    // Motor driver failed, MotorsSm is faulted
+    Driver(DriverError),
 }
 
-pub struct MotorsSm<'a> {
+pub struct MotorsSm<D: MotorDriver> {
     current_cmd: Option<MotorsSmCommand>,
+    queue: Deque<MotorsSmCommand, QUEUE_SIZE>,
+    interrupt: Option<Interrupt>,
+    paused_cmd: Option<MotorsSmCommand>,
+    // Complete event of a move paused with nothing left of it, reported on Resume
+    held_event: Option<MotionEvent>,
+    cancelled_cmd: Option<MotorsSmCommand>,
+    // Move being executed, current_cmd may be replaced by EmergencyStop
+    active_cmd: Option<MotorsSmCommand>,
+    started: Instant,
+    event: Option<MotionEvent>,
+    sweep: Option<Sweep>,
+    // Pulses at the start of the speed measurement, None while the duty settles
+    sweep_start: Option<u32>,
+    // Config was changed by learning or calibration and isn't saved yet
+    config_dirty: bool,
+    fault: Option<DriverError>,
     state: MotorSmState,
-    motors: Motors<'a>,
+    motors: Motors<D>,
     last_left_pulses: u32,
     last_right_pulses: u32,
+    targets: (u32, u32),
+    speeds: (u8, u8),
+    progress_pulses: u32,
+    progress_time: Instant,
+    left_stopped: bool,
+    right_stopped: bool,
+    last_turn_angle: u16,
+    move_time: u64,
+    move_start: Instant,
+    // Direction each wheel last moved in, None until it has moved
+    last_directions: (Option<Direction>, Option<Direction>),
+    // Wheels reversing at the start of the current move
+    reversed: (bool, bool),
+    // Time from the start of the current move to the first pulse of each wheel
+    start_latency: (Option<u64>, Option<u64>),
+    // Average start latency of wheels that keep their direction, for drive and turn moves
+    latency_baseline: [Option<u64>; 2],
+    traction_time: Instant,
+    traction_pulses: (u32, u32),
+    slips: u16,
+    drags: u16,
+    #[cfg(feature = "pid")]
+    speed_time: Instant,
+    #[cfg(feature = "pid")]
+    speed_pulses: (u32, u32),
+    #[cfg(feature = "pid")]
+    duties: (u8, u8),
     #[cfg(feature = "pid")]
     left_pid: Pid,
     #[cfg(feature = "pid")]
     right_pid: Pid,
+    #[cfg(feature = "pid")]
+    sync_pid: Pid,
+    #[cfg(feature = "pid")]
+    left_turn_pid: Pid,
+    #[cfg(feature = "pid")]
+    right_turn_pid: Pid,
+    #[cfg(feature = "pid")]
+    tune: Option<AutoTune>,
 }
 
-impl<'a> MotorsSm<'a> {
-    pub fn init(motors: Motors<'a>) -> Self {
-        #[cfg(feature = "pid")]
-        let make_pid = |m: &Motors| {
-            Pid::new(
-                m.config.pid_kp,
-                m.config.pid_ki,
-                m.config.pid_kd,
-                m.config.pid_integral_limit,
-            )
-        };
+#[cfg(feature = "pid")]
+fn speed_pid(config: &Config) -> Pid {
+    Pid::new(
+        config.pid_kp,
+        config.pid_ki,
+        config.pid_kd,
+        config.pid_integral_limit,
+    )
+}
+
+#[cfg(feature = "pid")]
+fn turn_pid(config: &Config) -> Pid {
+    Pid::new(
+        config.turn_kp,
+        config.turn_ki,
+        config.turn_kd,
+        config.turn_integral_limit,
+    )
+}
+
+// Right encoder counts in steps of 2, so the count difference is noisy
+#[cfg(feature = "pid")]
+fn sync_pid(config: &Config) -> Pid {
+    Pid::new(
+        config.sync_kp,
+        config.sync_ki,
+        config.sync_kd,
+        config.sync_integral_limit,
+    )
+    .with_derivative_filter(128)
+}
+
+impl<D: MotorDriver> MotorsSm<D> {
+    pub fn init(motors: Motors<D>) -> Self {
         Self {
             current_cmd: None,
+            queue: Deque::new(),
+            interrupt: None,
+            paused_cmd: None,
+            held_event: None,
+            cancelled_cmd: None,
+            active_cmd: None,
+            started: Instant::now(),
+            event: None,
+            sweep: None,
+            sweep_start: None,
+            config_dirty: false,
+            fault: None,
             state: MotorSmState::Stopped,
             last_left_pulses: 0,
             last_right_pulses: 0,
+            targets: (0, 0),
+            speeds: (100, 100),
+            progress_pulses: 0,
+            progress_time: Instant::now(),
+            left_stopped: false,
+            right_stopped: false,
+            last_turn_angle: 0,
+            move_time: 0,
+            move_start: Instant::now(),
+            last_directions: (None, None),
+            reversed: (false, false),
+            start_latency: (None, None),
+            latency_baseline: [None, None],
+            traction_time: Instant::now(),
+            traction_pulses: (0, 0),
+            slips: 0,
+            drags: 0,
+            #[cfg(feature = "pid")]
+            speed_time: Instant::now(),
+            #[cfg(feature = "pid")]
+            speed_pulses: (0, 0),
+            #[cfg(feature = "pid")]
+            duties: (0, 0),
+            #[cfg(feature = "pid")]
+            left_pid: speed_pid(&motors.config),
+            #[cfg(feature = "pid")]
+            right_pid: speed_pid(&motors.config),
             #[cfg(feature = "pid")]
-            left_pid: make_pid(&motors),
+            sync_pid: sync_pid(&motors.config),
             #[cfg(feature = "pid")]
-            right_pid: make_pid(&motors),
+            left_turn_pid: turn_pid(&motors.config),
+            #[cfg(feature = "pid")]
+            right_turn_pid: turn_pid(&motors.config),
+            #[cfg(feature = "pid")]
+            tune: None,
             motors,
         }
     }
@@ -269,206 +1047,2024 @@ impl<'a> MotorsSm<'a> {
         self.left_pid.reset();
         #[cfg(feature = "pid")]
         self.right_pid.reset();
+        #[cfg(feature = "pid")]
+        self.sync_pid.reset();
+        #[cfg(feature = "pid")]
+        self.left_turn_pid.reset();
+        #[cfg(feature = "pid")]
+        self.right_turn_pid.reset();
     }
 
-    pub fn process(&mut self) -> u64 {
-        log::debug!("from: {:?}", self.state);
-        let res = match self.state {
-            MotorSmState::Stopped => {
-                if let Some(cmd) = self.current_cmd {
-                    match cmd {
-                        MotorsSmCommand::Forward(_) => {
-                            MOTOR1_PULSES.store(0, Ordering::Relaxed);
-                            MOTOR2_PULSES.store(0, Ordering::Relaxed);
-                            self.state = MotorSmState::WaitAccel;
-                            self.motors.forward() as u64
-                        }
-                        MotorsSmCommand::Backwards(_) => {
-                            MOTOR1_PULSES.store(0, Ordering::Relaxed);
-                            MOTOR2_PULSES.store(0, Ordering::Relaxed);
-                            self.state = MotorSmState::WaitAccel;
-                            self.motors.backwards() as u64
-                        }
-                        MotorsSmCommand::Left(_) => {
-                            MOTOR1_PULSES.store(0, Ordering::Relaxed);
-                            MOTOR2_PULSES.store(0, Ordering::Relaxed);
-                            self.state = MotorSmState::WaitAccel;
-                            self.motors.left() as u64
-                        }
-                        MotorsSmCommand::Right(_) => {
-                            MOTOR1_PULSES.store(0, Ordering::Relaxed);
-                            MOTOR2_PULSES.store(0, Ordering::Relaxed);
-                            self.state = MotorSmState::WaitAccel;
-                            self.motors.right() as u64
-                        }
-                        MotorsSmCommand::EmergencyStop => {
-                            self.motors.emergency_stop();
-                            self.reset_all_pids();
-                            self.state = MotorSmState::Stopped;
-                            self.current_cmd = None;
-                            0
-                        }
-                        MotorsSmCommand::Stop => {
-                            self.state = MotorSmState::Stopped;
-                            self.current_cmd = None;
-                            0
-                        }
-                    }
-                } else {
-                    // Stopped and no command. Do nothing
-                    0
-                }
-            }
-            MotorSmState::WaitAccel => {
-                if let Some(cmd) = self.current_cmd {
-                    match cmd {
-                        MotorsSmCommand::Forward(delay) => {
-                            self.state = MotorSmState::Forward;
-                            delay
-                        }
-                        MotorsSmCommand::Backwards(delay) => {
-                            self.state = MotorSmState::Backwards;
-                            delay
-                        }
-                        MotorsSmCommand::Left(left) => {
-                            self.state = MotorSmState::Left;
-                            left
-                        }
-                        MotorsSmCommand::Right(right) => {
-                            self.state = MotorSmState::Right;
-                            right
-                        }
-                        MotorsSmCommand::EmergencyStop => {
-                            self.motors.emergency_stop();
-                            self.reset_all_pids();
-                            self.state = MotorSmState::Stopped;
-                            self.current_cmd = None;
-                            0
-                        }
-                        _ => {
-                            log::info!(
-                                "{:?} state with {:?} command. Stopping motors",
-                                self.state,
-                                cmd
-                            );
-                            self.state = MotorSmState::WaitDecel;
-                            self.motors.stop() as u64
-                        }
-                    }
-                } else {
-                    log::info!("{:?} state with no command. Stopping motors", self.state);
-                    self.state = MotorSmState::WaitDecel;
-                    self.motors.stop() as u64
-                }
+    fn stop_motors(&mut self, mode: StopMode) -> Result<u64, DriverError> {
+        self.state = MotorSmState::WaitDecel;
+        match mode {
+            StopMode::Coast => {
+                self.motors.stop();
+                Ok(CONTROL_PERIOD)
             }
-            MotorSmState::Forward
+            StopMode::Brake => Ok(self.motors.brake()? as u64),
+        }
+    }
+
+    // Aborts the current move and drops everything waiting
+    fn abort(&mut self) {
+        self.finish_move(MotionOutcome::Aborted);
+        self.reset_all_pids();
+        self.current_cmd = None;
+        self.queue.clear();
+        self.interrupt = None;
+        self.paused_cmd = None;
+        self.held_event = None;
+        self.sweep = None;
+        #[cfg(feature = "pid")]
+        {
+            self.tune = None;
+        }
+    }
+
+    fn emergency_stop(&mut self) -> Result<u64, DriverError> {
+        self.abort();
+        match self.motors.config.emergency_stop_mode {
+            StopMode::Coast => {
+                self.state = MotorSmState::Stopped;
+                Ok(self.motors.emergency_stop()? as u64)
+            }
+            StopMode::Brake => {
+                self.state = MotorSmState::Braking;
+                Ok(self.motors.brake()? as u64)
+            }
+        }
+    }
+
+    // The motors may be left in any state after a driver error, so all outputs are turned off
+    // and no commands are accepted until clear_fault()
+    fn enter_fault(&mut self, error: DriverError) -> u64 {
+        log::error!("Motor driver error: {:?}", error);
+        self.abort();
+        if let Err(e) = self.motors.disable() {
+            log::error!("Failed to turn motor outputs off: {:?}", e);
+        }
+        self.fault = Some(error);
+        self.state = MotorSmState::Faulted;
+        0
+    }
+
+    // Takes the next queued command, if any. Returns the delay before it should be processed
+    fn next_cmd(&mut self) -> u64 {
+        self.current_cmd = self.queue.pop_front();
+        if self.current_cmd.is_some() {
+            CONTROL_PERIOD
+        } else {
+            0
+        }
+    }
+
+    fn finish_move(&mut self, outcome: MotionOutcome) {
+        self.learn_backlash();
+        if self.motors.left.duty > 0 || MOTOR1_PULSES.load(Ordering::Relaxed) > 0 {
+            self.last_directions.0 = Some(self.motors.left.direction);
+        }
+        if self.motors.right.duty > 0 || MOTOR2_PULSES.load(Ordering::Relaxed) > 0 {
+            self.last_directions.1 = Some(self.motors.right.direction);
+        }
+        if let Some(cmd) = self.active_cmd.take() {
+            let event = MotionEvent {
+                outcome,
+                cmd,
+                left_pulses: MOTOR1_PULSES.load(Ordering::Relaxed),
+                right_pulses: MOTOR2_PULSES.load(Ordering::Relaxed),
+                duration: self.started.elapsed().as_millis(),
+            };
+            log::info!("{:?}", event);
+            self.event = Some(event);
+        }
+    }
+
+    // Command that would finish an interrupted move, or None if nothing is left of it.
+    // Encoder-based moves keep the part of their amount the wheels haven't travelled yet
+    fn remainder(&self, cmd: MotorsSmCommand) -> Option<MotorsSmCommand> {
+        let time_left = self
+            .move_time
+            .saturating_sub(self.move_start.elapsed().as_millis());
+        let total = (self.targets.0 + self.targets.1) as u64;
+        let done = (self.last_left_pulses + self.last_right_pulses) as u64;
+        let left = |amount: u32| (amount as u64 * total.saturating_sub(done) / total.max(1)) as u32;
+        let remainder = match cmd {
+            MotorsSmCommand::Forward(_) => MotorsSmCommand::Forward(time_left),
+            MotorsSmCommand::Backwards(_) => MotorsSmCommand::Backwards(time_left),
+            MotorsSmCommand::Left(_) => MotorsSmCommand::Left(time_left),
+            MotorsSmCommand::Right(_) => MotorsSmCommand::Right(time_left),
+            MotorsSmCommand::ForwardDistance(mm, speed) => {
+                MotorsSmCommand::ForwardDistance(left(mm), speed)
+            }
+            MotorsSmCommand::BackwardsDistance(mm, speed) => {
+                MotorsSmCommand::BackwardsDistance(left(mm), speed)
+            }
+            MotorsSmCommand::TurnLeft(deg, speed) => {
+                MotorsSmCommand::TurnLeft(left(deg as u32) as u16, speed)
+            }
+            MotorsSmCommand::TurnRight(deg, speed) => {
+                MotorsSmCommand::TurnRight(left(deg as u32) as u16, speed)
+            }
+            MotorsSmCommand::Arc {
+                radius_mm,
+                length_mm,
+                speed,
+            } => MotorsSmCommand::Arc {
+                radius_mm,
+                length_mm: left(length_mm),
+                speed,
+            },
+            _ => return None,
+        };
+        let empty = match remainder {
+            MotorsSmCommand::Forward(t)
+            | MotorsSmCommand::Backwards(t)
+            | MotorsSmCommand::Left(t)
+            | MotorsSmCommand::Right(t) => t == 0,
+            MotorsSmCommand::ForwardDistance(mm, _)
+            | MotorsSmCommand::BackwardsDistance(mm, _)
+            | MotorsSmCommand::Arc { length_mm: mm, .. } => mm == 0,
+            MotorsSmCommand::TurnLeft(deg, _) | MotorsSmCommand::TurnRight(deg, _) => deg == 0,
+            _ => true,
+        };
+        (!empty).then_some(remainder)
+    }
+
+    // Command that starts next while no move runs. The queue moves on without it
+    fn take_next(&mut self) -> Option<MotorsSmCommand> {
+        if let MotorSmState::Braking = self.state {
+            self.queue.pop_front()
+        } else {
+            let cmd = self.current_cmd.take();
+            self.current_cmd = self.queue.pop_front();
+            cmd
+        }
+    }
+
+    // True from the start of a move until its deceleration is done
+    fn moving(&self) -> bool {
+        #[cfg(feature = "pid")]
+        if matches!(self.state, MotorSmState::AutoTuning) {
+            return true;
+        }
+        matches!(
+            self.state,
+            MotorSmState::WaitAccel
+                | MotorSmState::Forward
+                | MotorSmState::Backwards
+                | MotorSmState::Left
+                | MotorSmState::Right
+                | MotorSmState::Arc
+                | MotorSmState::WaitDecel
+                | MotorSmState::Calibrating
+                | MotorSmState::Waiting
+        )
+    }
+
+    // Ends calibration, auto-tune or a wait early, without a deceleration. A paused one
+    // runs `cmd` on Resume: calibration and auto-tune start over, a wait waits the rest
+    fn interrupt_routine(
+        &mut self,
+        interrupt: Interrupt,
+        cmd: MotorsSmCommand,
+    ) -> Result<u64, DriverError> {
+        log::info!("{:?} interrupted by {:?}", cmd, interrupt);
+        self.motors.coast()?;
+        self.current_cmd = None;
+        match interrupt {
+            Interrupt::Cancel(_) => {
+                self.finish_move(MotionOutcome::Cancelled);
+                self.cancelled_cmd = None;
+                self.state = MotorSmState::Stopped;
+                Ok(self.next_cmd())
+            }
+            Interrupt::Pause => {
+                self.finish_move(MotionOutcome::Paused);
+                self.paused_cmd = Some(cmd);
+                self.state = MotorSmState::Paused;
+                Ok(0)
+            }
+        }
+    }
+
+    fn start_calibration(&mut self) -> Result<u64, DriverError> {
+        log::info!("Starting motor calibration");
+        self.active_cmd = Some(MotorsSmCommand::Calibrate);
+        self.started = Instant::now();
+        self.reset_pulses();
+        let sweep = Sweep::new();
+        if let Some((side, direction, duty)) = sweep.current() {
+            self.motors.run_wheel(side, direction, duty)?;
+        }
+        self.sweep = Some(sweep);
+        self.sweep_start = None;
+        self.state = MotorSmState::Calibrating;
+        Ok(CALIBRATION_SETTLE)
+    }
+
+    fn calibrate(&mut self) -> Result<u64, DriverError> {
+        if let Some(MotorsSmCommand::EmergencyStop) = self.current_cmd {
+            return self.emergency_stop();
+        }
+        if let Some(interrupt) = self.interrupt.take() {
+            self.sweep = None;
+            return self.interrupt_routine(interrupt, MotorsSmCommand::Calibrate);
+        }
+        let Some(sweep) = self.sweep.as_mut() else {
+            self.state = MotorSmState::Stopped;
+            return Ok(self.next_cmd());
+        };
+        let Some((side, _, duty)) = sweep.current() else {
+            return Ok(0);
+        };
+        let pulses = match side {
+            Side::Left => MOTOR1_PULSES.load(Ordering::Relaxed),
+            Side::Right => MOTOR2_PULSES.load(Ordering::Relaxed),
+        };
+        let Some(start) = self.sweep_start.take() else {
+            // Duty has settled, measure from now
+            self.sweep_start = Some(pulses);
+            return Ok(CALIBRATION_MEASURE);
+        };
+
+        let speed = ((pulses - start) as u64 * 1000 / CALIBRATION_MEASURE) as u16;
+        log::debug!(
+            "Calibration: {:?} wheel, duty {}, {} pulses/s",
+            side,
+            duty,
+            speed
+        );
+        let next_wheel = sweep.record(speed);
+        match sweep.current() {
+            Some((side, direction, duty)) => {
+                if next_wheel {
+                    self.motors.coast()?;
+                }
+                self.motors.run_wheel(side, direction, duty)?;
+                if next_wheel {
+                    Ok(CALIBRATION_PAUSE)
+                } else {
+                    Ok(CALIBRATION_SETTLE)
+                }
+            }
+            None => {
+                let result = sweep.result();
+                log::info!("Calibration done: {:?}", result);
+                self.motors.coast()?;
+                self.motors.config.calibration = Some(result);
+                self.config_dirty = true;
+                self.sweep = None;
+                self.finish_move(MotionOutcome::Complete);
+                self.state = MotorSmState::Stopped;
+                Ok(self.next_cmd())
+            }
+        }
+    }
+
+    // Relay around the open-loop duty of the nominal speed, one wheel at a time
+    #[cfg(feature = "pid")]
+    fn relay(&self) -> Relay {
+        Relay::new(
+            self.motors.config.target_speed as i32,
+            AUTOTUNE_AMPLITUDE,
+            AUTOTUNE_HYSTERESIS,
+        )
+    }
+
+    #[cfg(feature = "pid")]
+    fn start_autotune(&mut self) -> Result<u64, DriverError> {
+        log::info!("Starting speed controller auto-tune");
+        self.active_cmd = Some(MotorsSmCommand::AutoTune);
+        self.started = Instant::now();
+        self.reset_pulses();
+        let base = self
+            .motors
+            .config
+            .base_duty(Side::Left, Direction::Forward, 100, false);
+        self.motors.run_wheel(
+            Side::Left,
+            Direction::Forward,
+            (base as i32 + AUTOTUNE_AMPLITUDE).min(100) as u8,
+        )?;
+        self.tune = Some(AutoTune::new(Side::Left, self.relay(), 0));
+        self.state = MotorSmState::AutoTuning;
+        Ok(AUTOTUNE_PERIOD)
+    }
+
+    #[cfg(feature = "pid")]
+    fn autotune(&mut self) -> Result<u64, DriverError> {
+        if let Some(MotorsSmCommand::EmergencyStop) = self.current_cmd {
+            return self.emergency_stop();
+        }
+        if let Some(interrupt) = self.interrupt.take() {
+            self.tune = None;
+            return self.interrupt_routine(interrupt, MotorsSmCommand::AutoTune);
+        }
+        let Some(tune) = self.tune.as_mut() else {
+            self.state = MotorSmState::Stopped;
+            return Ok(self.next_cmd());
+        };
+        let side = tune.side;
+        let pulses = match side {
+            Side::Left => MOTOR1_PULSES.load(Ordering::Relaxed),
+            Side::Right => MOTOR2_PULSES.load(Ordering::Relaxed),
+        };
+        let speed = tune.measure(pulses);
+        let elapsed = tune.start.elapsed().as_millis();
+        let offset = tune.relay.update(elapsed, speed);
+
+        let timed_out = elapsed > AUTOTUNE_TIMEOUT;
+        if !tune.relay.done() && !timed_out {
+            let base = self
+                .motors
+                .config
+                .base_duty(side, Direction::Forward, 100, false);
+            let duty = (base as i32 + offset).clamp(0, 100) as u8;
+            self.motors.run_wheel(side, Direction::Forward, duty)?;
+            return Ok(AUTOTUNE_PERIOD);
+        }
+
+        let result = tune.relay.result();
+        match result {
+            Some(result) => log::info!("Auto-tune: {:?} wheel {:?}", side, result),
+            None => log::warn!("Auto-tune: {:?} wheel didn't oscillate", side),
+        }
+        self.motors.coast()?;
+        match side {
+            Side::Left => {
+                tune.results[0] = result;
+                let relay = self.relay();
+                if let Some(tune) = self.tune.as_mut() {
+                    tune.restart(Side::Right, relay, MOTOR2_PULSES.load(Ordering::Relaxed));
+                }
+                let base =
+                    self.motors
+                        .config
+                        .base_duty(Side::Right, Direction::Forward, 100, false);
+                self.motors.run_wheel(
+                    Side::Right,
+                    Direction::Forward,
+                    (base as i32 + AUTOTUNE_AMPLITUDE).min(100) as u8,
+                )?;
+                Ok(AUTOTUNE_PERIOD)
+            }
+            Side::Right => {
+                tune.results[1] = result;
+                let results = tune.results;
+                self.tune = None;
+                self.apply_autotune(results);
+                self.finish_move(MotionOutcome::Complete);
+                self.state = MotorSmState::Stopped;
+                Ok(self.next_cmd())
+            }
+        }
+    }
+
+    // Both wheels share the speed controller gains, so the results are averaged.
+    // Gains are only changed if both wheels oscillated
+    #[cfg(feature = "pid")]
+    fn apply_autotune(&mut self, results: [Option<UltimateGain>; 2]) {
+        let [Some(left), Some(right)] = results else {
+            log::warn!("Auto-tune failed, speed controller gains not changed");
+            return;
+        };
+        let (left_kp, left_ki, left_kd) = left.gains(CONTROL_PERIOD as u32);
+        let (right_kp, right_ki, right_kd) = right.gains(CONTROL_PERIOD as u32);
+        let config = &mut self.motors.config;
+        config.pid_kp = (left_kp + right_kp) / 2;
+        config.pid_ki = (left_ki + right_ki) / 2;
+        config.pid_kd = (left_kd + right_kd) / 2;
+        // Gains are sent in telemetry too, and saved with the config of the surface
+        log::info!(
+            "Auto-tune done: kp={} ki={} kd={} (scaled by 256)",
+            config.pid_kp,
+            config.pid_ki,
+            config.pid_kd
+        );
+        self.left_pid
+            .set_gains(config.pid_kp, config.pid_ki, config.pid_kd);
+        self.right_pid
+            .set_gains(config.pid_kp, config.pid_ki, config.pid_kd);
+        self.config_dirty = true;
+    }
+
+    fn start_move(&mut self, cmd: MotorsSmCommand) {
+        self.active_cmd = Some(cmd);
+        self.started = Instant::now();
+        self.reset_pulses();
+        self.speeds = self.command_speeds(cmd);
+        let profile = if turn_cmd(cmd) {
+            self.motors.config.turn_profile
+        } else {
+            self.motors.config.drive_profile
+        };
+        self.motors.set_profile(profile);
+
+        let (left, right) = cmd_directions(cmd);
+        self.reversed = (
+            self.last_directions.0.is_some_and(|d| d != left),
+            self.last_directions.1.is_some_and(|d| d != right),
+        );
+        self.start_latency = (None, None);
+        self.traction_time = Instant::now();
+        self.traction_pulses = (0, 0);
+        if self.reversed.0 || self.reversed.1 {
+            log::debug!("Reversing wheels: {:?}", self.reversed);
+        }
+        self.state = MotorSmState::WaitAccel;
+    }
+
+    // Notes when each wheel starts counting pulses
+    fn watch_start(&mut self) {
+        let elapsed = self.started.elapsed().as_millis();
+        if self.start_latency.0.is_none() && MOTOR1_PULSES.load(Ordering::Relaxed) > 0 {
+            self.start_latency.0 = Some(elapsed);
+        }
+        if self.start_latency.1.is_none() && MOTOR2_PULSES.load(Ordering::Relaxed) > 0 {
+            self.start_latency.1 = Some(elapsed);
+        }
+    }
+
+    // Backlash time is how much later a reversing wheel starts counting than one that keeps
+    // its direction. Drive and turn moves ramp differently, so they have separate baselines
+    fn learn_backlash(&mut self) {
+        let Some(cmd) = self.active_cmd else {
+            return;
+        };
+        let baseline = &mut self.latency_baseline[turn_cmd(cmd) as usize];
+        let wheels = [
+            (self.start_latency.0, self.reversed.0),
+            (self.start_latency.1, self.reversed.1),
+        ];
+        let config = &mut self.motors.config;
+        for (latency, reversed) in wheels {
+            let Some(latency) = latency else {
+                continue;
+            };
+            if !reversed {
+                *baseline = Some(baseline.map_or(latency, |b| (3 * b + latency) / 4));
+                continue;
+            }
+            if let Some(b) = *baseline {
+                let sample = latency.saturating_sub(b);
+                let backlash_time = ((3 * config.backlash_time as u64 + sample) / 4) as u16;
+                if backlash_time != config.backlash_time {
+                    config.backlash_time = backlash_time;
+                    self.config_dirty = true;
+                    log::info!("Backlash time: {}ms", backlash_time);
+                }
+            }
+        }
+        self.start_latency = (None, None);
+        self.reversed = (false, false);
+    }
+
+    fn reset_pulses(&mut self) {
+        MOTOR1_PULSES.store(0, Ordering::Relaxed);
+        MOTOR2_PULSES.store(0, Ordering::Relaxed);
+    }
+
+    // Average of both wheels, so a slightly faster wheel doesn't end the move early
+    fn distance_pulses(&self) -> u32 {
+        (MOTOR1_PULSES.load(Ordering::Relaxed) + MOTOR2_PULSES.load(Ordering::Relaxed)) / 2
+    }
+
+    // Learned duty is stored for the nominal speed, so only moves at full speed are learned
+    // from. A duty held at a limit didn't settle and is skipped too. Flash is only written
+    // when it changes
+    #[cfg(feature = "pid")]
+    fn learn_duty(&mut self) {
+        if self.speeds != (100, 100) {
+            return;
+        }
+        let config = &self.motors.config;
+        let settled = |side, direction, duty| {
+            let (min, max) = config.duty_limits(side, direction, 100);
+            duty > min && duty < max && duty < 100
+        };
+        if !settled(Side::Left, self.motors.left.direction, self.duties.0)
+            || !settled(Side::Right, self.motors.right.direction, self.duties.1)
+        {
+            return;
+        }
+        let config = &mut self.motors.config;
+        if self.duties != (config.left_duty, config.right_duty) {
+            (config.left_duty, config.right_duty) = self.duties;
+            self.config_dirty = true;
+            log::info!(
+                "Duty adjusted: left={} right={}",
+                self.duties.0,
+                self.duties.1
+            );
+        }
+    }
+
+    #[cfg(feature = "pid")]
+    fn start_speed_control(&mut self) {
+        self.reset_all_pids();
+        self.speed_time = Instant::now();
+        self.speed_pulses = (
+            MOTOR1_PULSES.load(Ordering::Relaxed),
+            MOTOR2_PULSES.load(Ordering::Relaxed),
+        );
+        self.duties = (
+            self.motors.base_duty(Side::Left, self.speeds.0),
+            self.motors.base_duty(Side::Right, self.speeds.1),
+        );
+    }
+
+    // Measures wheel speed since the previous update and corrects duty to hold target speed,
+    // with the turn controller while spinning in place. The wheel that got ahead is also
+    // slowed down and the other one sped up, so turns are symmetric and straight moves straight
+    #[cfg(feature = "pid")]
+    fn update_speed(&mut self, turn: bool) -> Result<(), DriverError> {
+        let dt = self.speed_time.elapsed().as_millis() as u32;
+        if dt == 0 {
+            return Ok(());
+        }
+        let left_pulses = MOTOR1_PULSES.load(Ordering::Relaxed);
+        let right_pulses = MOTOR2_PULSES.load(Ordering::Relaxed);
+        let left_speed = ((left_pulses - self.speed_pulses.0) * 1000 / dt) as i32;
+        let right_speed = ((right_pulses - self.speed_pulses.1) * 1000 / dt) as i32;
+        self.speed_time = Instant::now();
+        self.speed_pulses = (left_pulses, right_pulses);
+
+        let config = &self.motors.config;
+        let (left_speed_pct, right_speed_pct) = self.speeds;
+        let (target_speed, left_pid, right_pid) = if turn {
+            (
+                config.turn_speed,
+                &mut self.left_turn_pid,
+                &mut self.right_turn_pid,
+            )
+        } else {
+            (config.target_speed, &mut self.left_pid, &mut self.right_pid)
+        };
+        let left_target = (target_speed * left_speed_pct as u32 / 100) as i32;
+        let right_target = (target_speed * right_speed_pct as u32 / 100) as i32;
+
+        // Pulses are reset at the start of each move, so these are cumulative for the move.
+        // Counts are weighted by wheel speeds, so arcs keep their ratio instead of equal counts
+        let error = (left_pulses as i32 * right_speed_pct as i32
+            - right_pulses as i32 * left_speed_pct as i32)
+            / left_speed_pct.max(right_speed_pct).max(1) as i32;
+        let sync_adj = self.sync_pid.update(0, error);
+
+        // Speed controllers output the duty. Open-loop duty and the sync correction are fed
+        // forward, so the controllers only handle the residual and don't wind up at the limits
+        let motors = &self.motors;
+        let duty = |pid: &mut Pid, side: Side, direction: Direction, target, speed, adj| {
+            let speed_pct = match side {
+                Side::Left => left_speed_pct,
+                Side::Right => right_speed_pct,
+            };
+            let (min, max) = motors.config.duty_limits(side, direction, speed_pct);
+            pid.set_output_limits(min as i32, max as i32);
+            let feed_forward = motors.base_duty(side, speed_pct) as i32 + adj;
+            pid.update_with_feed_forward(target, speed, feed_forward) as u8
+        };
+        self.duties = (
+            duty(
+                left_pid,
+                Side::Left,
+                motors.left.direction,
+                left_target,
+                left_speed,
+                sync_adj,
+            ),
+            duty(
+                right_pid,
+                Side::Right,
+                motors.right.direction,
+                right_target,
+                right_speed,
+                -sync_adj,
+            ),
+        );
+        self.motors.set_duty(self.duties.0, self.duties.1)?;
+        log::debug!(
+            "Speed: left={} right={}, duty: left={} right={}",
+            left_speed,
+            right_speed,
+            self.duties.0,
+            self.duties.1
+        );
+        Ok(())
+    }
+
+    fn start_timed(&mut self, delay: u64) -> u64 {
+        self.move_time = if self.reversed.0 || self.reversed.1 {
+            delay + self.motors.config.backlash_time as u64
+        } else {
+            delay
+        };
+        self.move_start = Instant::now();
+        #[cfg(feature = "pid")]
+        self.start_speed_control();
+        CONTROL_PERIOD
+    }
+
+    fn start_distance(&mut self, mm: u32) -> u64 {
+        #[cfg(feature = "pid")]
+        self.start_speed_control();
+        let pulses = self.motors.config.mm_to_pulses(mm);
+        self.targets = (pulses, pulses);
+        self.progress_pulses = self.distance_pulses();
+        self.progress_time = Instant::now();
+        log::info!("Distance move: {}mm, {} pulses", mm, pulses);
+        CONTROL_PERIOD
+    }
+
+    fn start_wheels(&mut self, targets: (u32, u32)) -> u64 {
+        #[cfg(feature = "pid")]
+        self.start_speed_control();
+        self.targets = targets;
+        self.progress_pulses = self.distance_pulses();
+        self.progress_time = Instant::now();
+        self.left_stopped = false;
+        self.right_stopped = false;
+        CONTROL_PERIOD
+    }
+
+    fn start_turn(&mut self, deg: u16) -> u64 {
+        let pulses = self.motors.config.deg_to_pulses(deg);
+        log::info!("Turn: {}deg, {} pulses per wheel", deg, pulses);
+        self.start_wheels((pulses, pulses))
+    }
+
+    fn start_arc(&mut self, radius_mm: i32, length_mm: u32) -> u64 {
+        let config = &self.motors.config;
+        let (left_r, right_r) = config.arc_radii(radius_mm);
+        let center_r = (left_r + right_r) / 2;
+        let targets = (
+            config.mm_to_pulses(length_mm * left_r / center_r),
+            config.mm_to_pulses(length_mm * right_r / center_r),
+        );
+        log::info!(
+            "Arc: radius {}mm, {}mm, left={} right={} pulses",
+            radius_mm,
+            length_mm,
+            targets.0,
+            targets.1
+        );
+        self.start_wheels(targets)
+    }
+
+    // Wheel speeds in percent of the nominal speed for a command
+    fn command_speeds(&self, cmd: MotorsSmCommand) -> (u8, u8) {
+        match cmd {
+            MotorsSmCommand::ForwardDistance(_, speed)
+            | MotorsSmCommand::BackwardsDistance(_, speed)
+            | MotorsSmCommand::TurnLeft(_, speed)
+            | MotorsSmCommand::TurnRight(_, speed) => (speed, speed),
+            MotorsSmCommand::Arc {
+                radius_mm, speed, ..
+            } => {
+                let (left_r, right_r) = self.motors.config.arc_radii(radius_mm);
+                let outer_r = left_r.max(right_r);
+                (
+                    (speed as u32 * left_r / outer_r) as u8,
+                    (speed as u32 * right_r / outer_r) as u8,
+                )
+            }
+            _ => (100, 100),
+        }
+    }
+
+    // Returns true if encoders haven't reported for too long
+    fn stalled(&mut self, pulses: u32) -> bool {
+        if pulses != self.progress_pulses {
+            self.progress_pulses = pulses;
+            self.progress_time = Instant::now();
+            false
+        } else if self.progress_time.elapsed().as_millis() > self.motors.config.stall_timeout {
+            log::warn!(
+                "No encoder pulses for {}ms, stopping at {}/{} pulses",
+                self.motors.config.stall_timeout,
+                pulses,
+                (self.targets.0 + self.targets.1) / 2
+            );
+            true
+        } else {
+            false
+        }
+    }
+
+    // Returns true when a distance-based move should start decelerating
+    fn distance_reached(&mut self) -> bool {
+        let pulses = self.distance_pulses();
+        pulses + self.motors.config.decel_pulses >= (self.targets.0 + self.targets.1) / 2
+            || self.stalled(pulses)
+    }
+
+    // Stops each wheel once it reaches its own target.
+    // Returns true when both wheels are stopped
+    fn wheels_reached(&mut self) -> Result<bool, DriverError> {
+        let (left_target, right_target) = self.targets;
+        let decel = self.motors.config.decel_pulses;
+        let brake = matches!(self.motors.config.stop_mode, StopMode::Brake);
+        if !self.left_stopped && MOTOR1_PULSES.load(Ordering::Relaxed) + decel >= left_target {
+            if brake {
+                self.motors.brake_left()?;
+            } else {
+                self.motors.stop_left();
+            }
+            self.left_stopped = true;
+        }
+        if !self.right_stopped && MOTOR2_PULSES.load(Ordering::Relaxed) + decel >= right_target {
+            if brake {
+                self.motors.brake_right()?;
+            } else {
+                self.motors.stop_right();
+            }
+            self.right_stopped = true;
+        }
+        Ok((self.left_stopped && self.right_stopped) || self.stalled(self.distance_pulses()))
+    }
+
+    // Compares measured wheel speeds with the calibrated speed curves at the current duty.
+    // Ramping wheels lag behind their duty, so only slip is detected while accelerating
+    // and nothing while ramping down, e.g. the inner wheel of a turn or an arc
+    fn check_traction(&mut self) {
+        let elapsed = self.traction_time.elapsed().as_millis();
+        if elapsed < TRACTION_WINDOW {
+            return;
+        }
+        let pulses = (
+            MOTOR1_PULSES.load(Ordering::Relaxed),
+            MOTOR2_PULSES.load(Ordering::Relaxed),
+        );
+        let speeds = (
+            ((pulses.0 - self.traction_pulses.0) as u64 * 1000 / elapsed) as u32,
+            ((pulses.1 - self.traction_pulses.1) as u64 * 1000 / elapsed) as u32,
+        );
+        self.traction_time = Instant::now();
+        self.traction_pulses = pulses;
+        let Some(calibration) = self.motors.config.calibration else {
+            return;
+        };
+
+        let traction = |side: Side, wheel: &Wheel, speed: u32| {
+            let expected = calibration.curve(side, wheel.direction).speed(wheel.duty);
+            if expected < TRACTION_MIN_SPEED {
+                None
+            } else if wheel.ramp.is_none_or(|ramp| ramp.accelerating())
+                && speed * 100 > expected * (100 + TRACTION_MARGIN)
+            {
+                Some(Traction::Slip)
+            } else if wheel.ramp.is_none() && speed * 100 < expected * (100 - TRACTION_MARGIN) {
+                Some(Traction::Drag)
+            } else {
+                None
+            }
+        };
+        let wheels = [
+            (
+                Side::Left,
+                traction(Side::Left, &self.motors.left, speeds.0),
+                speeds.0,
+            ),
+            (
+                Side::Right,
+                traction(Side::Right, &self.motors.right, speeds.1),
+                speeds.1,
+            ),
+        ];
+        for (side, traction, speed) in wheels {
+            match traction {
+                Some(Traction::Slip) => {
+                    log::warn!("{:?} wheel slipping, {} pulses/s", side, speed);
+                    self.slips = self.slips.wrapping_add(1);
+                    self.ease_acceleration();
+                }
+                Some(Traction::Drag) => {
+                    log::warn!("{:?} wheel dragged, {} pulses/s", side, speed);
+                    self.drags = self.drags.wrapping_add(1);
+                }
+                None => (),
+            }
+        }
+    }
+
+    // Ramps still accelerating continue with twice the acceleration time
+    fn ease_acceleration(&mut self) {
+        let accelerating = |wheel: &Wheel| wheel.ramp.is_some_and(|ramp| ramp.accelerating());
+        let mut profile = self.motors.profile;
+        if profile.accel_time >= MAX_SLIP_ACCEL_TIME
+            || !(accelerating(&self.motors.left) || accelerating(&self.motors.right))
+        {
+            return;
+        }
+        profile.accel_time = (profile.accel_time * 2).min(MAX_SLIP_ACCEL_TIME);
+        log::info!("Acceleration time raised to {}ms", profile.accel_time);
+        self.motors.set_profile(profile);
+    }
+
+    // Returns true while an encoder-based move hasn't reached its target yet
+    fn move_in_progress(&mut self) -> Result<bool, DriverError> {
+        self.watch_start();
+        self.check_traction();
+        // Wheels stopped separately during turns are still ramping down
+        self.motors.update()?;
+        let in_progress = match self.current_cmd {
+            Some(
+                MotorsSmCommand::Forward(_)
+                | MotorsSmCommand::Backwards(_)
+                | MotorsSmCommand::Left(_)
+                | MotorsSmCommand::Right(_),
+            ) => self.move_start.elapsed().as_millis() < self.move_time,
+            Some(MotorsSmCommand::ForwardDistance(..) | MotorsSmCommand::BackwardsDistance(..)) => {
+                !self.distance_reached()
+            }
+            Some(
+                MotorsSmCommand::TurnLeft(..)
+                | MotorsSmCommand::TurnRight(..)
+                | MotorsSmCommand::Arc { .. },
+            ) => !self.wheels_reached()?,
+            _ => false,
+        };
+        #[cfg(feature = "pid")]
+        if in_progress {
+            let turn = matches!(self.state, MotorSmState::Left | MotorSmState::Right);
+            self.update_speed(turn)?;
+        }
+        Ok(in_progress)
+    }
+
+    pub fn process(&mut self) -> u64 {
+        log::debug!("from: {:?}", self.state);
+        let res = match self.step() {
+            Ok(delay) => delay,
+            Err(e) => self.enter_fault(e),
+        };
+        log::debug!("to: {:?}, delay: {}", self.state, res);
+        res
+    }
+
+    fn step(&mut self) -> Result<u64, DriverError> {
+        Ok(match self.state {
+            MotorSmState::Stopped => {
+                if let Some(cmd) = self.current_cmd {
+                    match cmd {
+                        MotorsSmCommand::Forward(_)
+                        | MotorsSmCommand::ForwardDistance(..)
+                        | MotorsSmCommand::Arc { .. } => {
+                            self.start_move(cmd);
+                            self.motors.forward(self.speeds.0, self.speeds.1)?;
+                            CONTROL_PERIOD
+                        }
+                        MotorsSmCommand::Backwards(_) | MotorsSmCommand::BackwardsDistance(..) => {
+                            self.start_move(cmd);
+                            self.motors.backwards(self.speeds.0, self.speeds.1)?;
+                            CONTROL_PERIOD
+                        }
+                        MotorsSmCommand::Left(_) | MotorsSmCommand::TurnLeft(..) => {
+                            self.start_move(cmd);
+                            self.motors.left(self.speeds.0, self.speeds.1)?;
+                            CONTROL_PERIOD
+                        }
+                        MotorsSmCommand::Right(_) | MotorsSmCommand::TurnRight(..) => {
+                            self.start_move(cmd);
+                            self.motors.right(self.speeds.0, self.speeds.1)?;
+                            CONTROL_PERIOD
+                        }
+                        MotorsSmCommand::Wait(time) => {
+                            self.active_cmd = Some(cmd);
+                            self.started = Instant::now();
+                            self.reset_pulses();
+                            self.move_time = time;
+                            self.move_start = Instant::now();
+                            self.state = MotorSmState::Waiting;
+                            CONTROL_PERIOD
+                        }
+                        MotorsSmCommand::EmergencyStop => self.emergency_stop()?,
+                        MotorsSmCommand::Calibrate => self.start_calibration()?,
+                        #[cfg(feature = "pid")]
+                        MotorsSmCommand::AutoTune => self.start_autotune()?,
+                        MotorsSmCommand::Stop(_)
+                        | MotorsSmCommand::Pause
+                        | MotorsSmCommand::Resume => {
+                            // Handled by process_cmd(), never queued
+                            self.next_cmd()
+                        }
+                    }
+                } else {
+                    // Stopped and no command. Do nothing
+                    0
+                }
+            }
+            MotorSmState::WaitAccel => {
+                self.watch_start();
+                if let Some(cmd) = self.current_cmd {
+                    match cmd {
+                        MotorsSmCommand::Forward(delay) => {
+                            self.state = MotorSmState::Forward;
+                            self.start_timed(delay)
+                        }
+                        MotorsSmCommand::Backwards(delay) => {
+                            self.state = MotorSmState::Backwards;
+                            self.start_timed(delay)
+                        }
+                        MotorsSmCommand::ForwardDistance(mm, _) => {
+                            self.state = MotorSmState::Forward;
+                            self.start_distance(mm)
+                        }
+                        MotorsSmCommand::BackwardsDistance(mm, _) => {
+                            self.state = MotorSmState::Backwards;
+                            self.start_distance(mm)
+                        }
+                        MotorsSmCommand::Left(delay) => {
+                            self.state = MotorSmState::Left;
+                            self.start_timed(delay)
+                        }
+                        MotorsSmCommand::Right(delay) => {
+                            self.state = MotorSmState::Right;
+                            self.start_timed(delay)
+                        }
+                        MotorsSmCommand::TurnLeft(deg, _) => {
+                            self.state = MotorSmState::Left;
+                            self.start_turn(deg)
+                        }
+                        MotorsSmCommand::TurnRight(deg, _) => {
+                            self.state = MotorSmState::Right;
+                            self.start_turn(deg)
+                        }
+                        MotorsSmCommand::Arc {
+                            radius_mm,
+                            length_mm,
+                            ..
+                        } => {
+                            self.state = MotorSmState::Arc;
+                            self.start_arc(radius_mm, length_mm)
+                        }
+                        MotorsSmCommand::EmergencyStop => self.emergency_stop()?,
+                        _ if self.motors.update()? => CONTROL_PERIOD,
+                        _ => {
+                            log::info!(
+                                "{:?} state with {:?} command. Stopping motors",
+                                self.state,
+                                cmd
+                            );
+                            let mode = match cmd {
+                                MotorsSmCommand::Stop(mode) => mode,
+                                _ => self.motors.config.stop_mode,
+                            };
+                            self.stop_motors(mode)?
+                        }
+                    }
+                } else {
+                    log::info!("{:?} state with no command. Stopping motors", self.state);
+                    self.stop_motors(self.motors.config.stop_mode)?
+                }
+            }
+            MotorSmState::Forward
             | MotorSmState::Backwards
             | MotorSmState::Left
-            | MotorSmState::Right => {
+            | MotorSmState::Right
+            | MotorSmState::Arc => {
                 if self
                     .current_cmd
                     .is_some_and(|c| matches!(c, MotorsSmCommand::EmergencyStop))
                 {
-                    self.motors.emergency_stop();
-                    self.reset_all_pids();
-                    self.state = MotorSmState::Stopped;
-                    self.current_cmd = None;
+                    self.emergency_stop()?
+                } else if let Some(interrupt) = self.interrupt {
+                    log::info!("{:?} state interrupted by {:?}", self.state, interrupt);
+                    let mode = match interrupt {
+                        Interrupt::Cancel(mode) => mode,
+                        Interrupt::Pause => self.motors.config.stop_mode,
+                    };
+                    self.stop_motors(mode)?
+                } else if self.move_in_progress()? {
+                    CONTROL_PERIOD
+                } else {
+                    self.stop_motors(self.motors.config.stop_mode)?
+                }
+            }
+            MotorSmState::Braking => {
+                self.motors.coast()?;
+                self.state = MotorSmState::Stopped;
+                if let Some(MotorsSmCommand::EmergencyStop) = self.current_cmd {
+                    // Another emergency stop during the hold drops what was queued since
+                    self.abort();
+                    0
+                } else if let Some(Interrupt::Pause) = self.interrupt.take() {
+                    self.state = MotorSmState::Paused;
                     0
                 } else {
-                    self.state = MotorSmState::WaitDecel;
-                    self.motors.stop() as u64
+                    self.next_cmd()
+                }
+            }
+            MotorSmState::Paused | MotorSmState::Faulted => 0,
+            MotorSmState::Waiting => {
+                let time_left = self
+                    .move_time
+                    .saturating_sub(self.move_start.elapsed().as_millis());
+                if let Some(MotorsSmCommand::EmergencyStop) = self.current_cmd {
+                    self.emergency_stop()?
+                } else if let Some(interrupt) = self.interrupt.take() {
+                    self.interrupt_routine(interrupt, MotorsSmCommand::Wait(time_left))?
+                } else if time_left > 0 {
+                    time_left.min(CONTROL_PERIOD)
+                } else {
+                    self.finish_move(MotionOutcome::Complete);
+                    self.state = MotorSmState::Stopped;
+                    self.next_cmd()
                 }
             }
+            MotorSmState::Calibrating => self.calibrate()?,
+            #[cfg(feature = "pid")]
+            MotorSmState::AutoTuning => self.autotune()?,
+            MotorSmState::WaitDecel
+                if matches!(self.current_cmd, Some(MotorsSmCommand::EmergencyStop)) =>
+            {
+                self.emergency_stop()?
+            }
+            MotorSmState::WaitDecel if self.motors.update()? => CONTROL_PERIOD,
             MotorSmState::WaitDecel => {
                 let left_pulses = crate::encoder::MOTOR1_PULSES.load(Ordering::Relaxed);
                 let right_pulses = crate::encoder::MOTOR2_PULSES.load(Ordering::Relaxed);
                 self.last_left_pulses = left_pulses;
                 self.last_right_pulses = right_pulses;
-                let left_pulses = left_pulses as i32;
-                let right_pulses = right_pulses as i32;
-
                 log::info!("Pulse counts: left={} right={}", left_pulses, right_pulses);
 
+                if matches!(
+                    self.current_cmd,
+                    Some(MotorsSmCommand::TurnLeft(..) | MotorsSmCommand::TurnRight(..))
+                ) {
+                    self.last_turn_angle = self
+                        .motors
+                        .config
+                        .pulses_to_deg((self.last_left_pulses + self.last_right_pulses) / 2);
+                    log::info!("Turned by {}deg", self.last_turn_angle);
+                }
+
+                // Next move starts from the duty the speed loop settled on.
+                // Turns load the motors differently, so only straight moves are learned from.
+                // Calibrated speed curves are not touched
                 #[cfg(feature = "pid")]
+                if matches!(
+                    self.current_cmd,
+                    Some(
+                        MotorsSmCommand::Forward(_)
+                            | MotorsSmCommand::Backwards(_)
+                            | MotorsSmCommand::ForwardDistance(..)
+                            | MotorsSmCommand::BackwardsDistance(..)
+                    )
+                ) && self.motors.config.calibration.is_none()
                 {
-                    let target = self.motors.config.target_forward as i32;
-                    let is_turn = matches!(
-                        self.current_cmd,
-                        Some(MotorsSmCommand::Left(_) | MotorsSmCommand::Right(_))
-                    );
-                    if !is_turn {
-                        let left_adj = self.left_pid.update(target, left_pulses);
-                        let right_adj = self.right_pid.update(target, right_pulses);
-                        let min = self.motors.config.min_duty as i32;
-                        let max = self.motors.config.max_duty as i32;
-                        self.motors.config.left_duty =
-                            (self.motors.config.left_duty as i32 + left_adj).clamp(min, max) as u8;
-                        self.motors.config.right_duty =
-                            (self.motors.config.right_duty as i32 + right_adj).clamp(min, max)
-                                as u8;
-                        log::info!(
-                            "Duty adjusted: left={} right={}",
-                            self.motors.config.left_duty,
-                            self.motors.config.right_duty,
-                        );
-                    }
+                    self.learn_duty();
                 }
 
-                self.state = MotorSmState::Stopped;
-                self.current_cmd = None;
-                0
+                // Release the brake after the hold time
+                self.motors.coast()?;
+                let remainder = self.current_cmd.and_then(|cmd| self.remainder(cmd));
+                match self.interrupt.take() {
+                    Some(Interrupt::Cancel(_)) => {
+                        log::info!("Move cancelled, remaining: {:?}", remainder);
+                        self.finish_move(MotionOutcome::Cancelled);
+                        self.cancelled_cmd = remainder;
+                        self.state = MotorSmState::Stopped;
+                        self.next_cmd()
+                    }
+                    Some(Interrupt::Pause) => {
+                        log::info!("Move paused, remaining: {:?}", remainder);
+                        if remainder.is_some() {
+                            self.finish_move(MotionOutcome::Paused);
+                        } else {
+                            self.finish_move(MotionOutcome::Complete);
+                            self.held_event = self.event.take();
+                        }
+                        self.paused_cmd = remainder;
+                        self.state = MotorSmState::Paused;
+                        self.current_cmd = None;
+                        0
+                    }
+                    None => {
+                        self.finish_move(MotionOutcome::Complete);
+                        self.state = MotorSmState::Stopped;
+                        self.next_cmd()
+                    }
+                }
             }
-        };
-        log::debug!("to: {:?}, delay: {}", self.state, res);
-        res
+        })
     }
 
     pub fn process_cmd(&mut self, new_cmd: MotorsSmCommand) -> Result<(), MotorsSmError> {
-        if let MotorsSmCommand::EmergencyStop = new_cmd {
-            self.current_cmd = Some(new_cmd);
-            Ok(())
-        } else {
-            match self.state {
-                MotorSmState::Stopped => {
-                    if self.current_cmd.is_some() {
-                        // A command is already accepted, but hasn't been processed yet, so SM is busy
-                        Err(MotorsSmError::Busy)
-                    } else {
-                        self.current_cmd = Some(new_cmd);
-                        Ok(())
+        if let Some(fault) = self.fault {
+            return Err(MotorsSmError::Driver(fault));
+        }
+        match new_cmd {
+            MotorsSmCommand::EmergencyStop if matches!(self.state, MotorSmState::Paused) => {
+                // Motors are already stopped, the paused move and the held queue are dropped
+                self.abort();
+                self.state = MotorSmState::Stopped;
+                Ok(())
+            }
+            MotorsSmCommand::EmergencyStop => {
+                // Pending commands are dropped in process()
+                self.current_cmd = Some(new_cmd);
+                Ok(())
+            }
+            MotorsSmCommand::Stop(mode) if self.moving() => {
+                // Decelerates in process(), the rest of the move is kept in cancelled_cmd
+                self.interrupt = Some(Interrupt::Cancel(mode));
+                Ok(())
+            }
+            MotorsSmCommand::Pause if self.moving() => {
+                self.interrupt = Some(Interrupt::Pause);
+                Ok(())
+            }
+            MotorsSmCommand::Pause if matches!(self.state, MotorSmState::Paused) => Ok(()),
+            MotorsSmCommand::Stop(_) if matches!(self.state, MotorSmState::Paused) => {
+                // Drops the paused move, the queue is still held until Resume
+                self.cancelled_cmd = self.paused_cmd.take();
+                Ok(())
+            }
+            MotorsSmCommand::Resume => {
+                if let MotorSmState::Paused = self.state {
+                    self.state = MotorSmState::Stopped;
+                    if let Some(event) = self.held_event.take() {
+                        self.event = Some(event);
                     }
+                    self.current_cmd = self.paused_cmd.take().or_else(|| self.queue.pop_front());
+                    Ok(())
+                } else {
+                    Err(MotorsSmError::NotPaused)
                 }
-                MotorSmState::Forward
-                | MotorSmState::Backwards
-                | MotorSmState::Left
-                | MotorSmState::Right => {
-                    if let MotorsSmCommand::Stop = new_cmd {
-                        // Accept command. Next state is WaitDecel
-                        Ok(())
-                    } else {
-                        Err(MotorsSmError::Busy)
-                    }
+            }
+            MotorsSmCommand::Stop(_) | MotorsSmCommand::Pause
+                if matches!(self.current_cmd, Some(MotorsSmCommand::EmergencyStop)) =>
+            {
+                // Pending emergency stop drops everything anyway
+                Ok(())
+            }
+            MotorsSmCommand::Stop(_) => {
+                // Nothing runs, so the move that would start next is cancelled.
+                // It never started, but it still reports an event like a running move
+                self.cancelled_cmd = self.take_next();
+                if let Some(cmd) = self.cancelled_cmd {
+                    self.event = Some(MotionEvent {
+                        outcome: MotionOutcome::Cancelled,
+                        cmd,
+                        left_pulses: 0,
+                        right_pulses: 0,
+                        duration: 0,
+                    });
                 }
-                MotorSmState::WaitAccel | MotorSmState::WaitDecel => {
-                    // Busy. Retry later
-                    Err(MotorsSmError::Busy)
+                Ok(())
+            }
+            MotorsSmCommand::Pause if matches!(self.state, MotorSmState::Braking) => {
+                // Queue is held once the brake is released
+                self.interrupt = Some(Interrupt::Pause);
+                Ok(())
+            }
+            MotorsSmCommand::Pause => {
+                // Command that didn't start yet runs on Resume
+                self.paused_cmd = self.current_cmd.take();
+                self.state = MotorSmState::Paused;
+                Ok(())
+            }
+            _ => {
+                if matches!(self.state, MotorSmState::Stopped) && self.current_cmd.is_none() {
+                    self.current_cmd = Some(new_cmd);
+                    Ok(())
+                } else {
+                    // Started once the current move and everything queued before it are done
+                    self.queue
+                        .push_back(new_cmd)
+                        .map_err(|_| MotorsSmError::QueueFull)
                 }
             }
         }
     }
 
+    // Commands waiting for the current move to finish, in execution order
+    pub fn pending(&self) -> impl Iterator<Item = &MotorsSmCommand> {
+        self.queue.iter()
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    // Drops all pending commands, the current move continues
+    pub fn clear_pending(&mut self) {
+        self.queue.clear();
+    }
+
+    // Drops a pending command, keeping the order of the rest
+    pub fn cancel_pending(&mut self, index: usize) -> Result<MotorsSmCommand, MotorsSmError> {
+        if index >= self.queue.len() {
+            return Err(MotorsSmError::NoSuchCommand);
+        }
+        for i in index..self.queue.len() - 1 {
+            self.queue.swap(i, i + 1);
+        }
+        self.queue.pop_back().ok_or(MotorsSmError::NoSuchCommand)
+    }
+
+    // Replaces a pending command, returns the replaced one
+    pub fn replace_pending(
+        &mut self,
+        index: usize,
+        cmd: MotorsSmCommand,
+    ) -> Result<MotorsSmCommand, MotorsSmError> {
+        let pending = self
+            .queue
+            .get_mut(index)
+            .ok_or(MotorsSmError::NoSuchCommand)?;
+        Ok(core::mem::replace(pending, cmd))
+    }
+
+    // Event of the last finished move, each event is returned once
+    pub fn take_event(&mut self) -> Option<MotionEvent> {
+        self.event.take()
+    }
+
     pub fn busy(&self) -> bool {
         !matches!(self.state, MotorSmState::Stopped)
     }
 
+    pub fn set_battery_voltage(&mut self, mv: u16) {
+        if let Err(e) = self.motors.set_voltage(mv) {
+            self.enter_fault(e);
+        }
+    }
+
+    // Driver error that faulted MotorsSm, if any
+    pub fn fault(&self) -> Option<DriverError> {
+        self.fault
+    }
+
+    // Leaves the faulted state once the driver accepts commands again
+    pub fn clear_fault(&mut self) -> Result<(), MotorsSmError> {
+        if self.fault.is_none() {
+            return Ok(());
+        }
+        self.motors.enable().map_err(MotorsSmError::Driver)?;
+        self.motors.coast().map_err(MotorsSmError::Driver)?;
+        log::info!("Motor driver fault cleared");
+        self.fault = None;
+        self.state = MotorSmState::Stopped;
+        Ok(())
+    }
+
+    // Battery compensation of the duty, in 1/1000
+    pub fn compensation(&self) -> u16 {
+        self.motors.compensation()
+    }
+
+    // Duties before battery compensation
     pub fn current_duties(&self) -> (u8, u8) {
-        (
-            self.motors.l1.max(self.motors.l2),
-            self.motors.r1.max(self.motors.r2),
-        )
+        (self.motors.left.duty, self.motors.right.duty)
+    }
+
+    // Takes effect immediately, a ramp in progress continues with the new timing
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.motors.set_profile(profile);
     }
 
     pub fn last_pulse_counts(&self) -> (u32, u32) {
         (self.last_left_pulses, self.last_right_pulses)
     }
+
+    // Slip and drag detected so far, as (slips, drags). Counters wrap around
+    pub fn traction_events(&self) -> (u16, u16) {
+        (self.slips, self.drags)
+    }
+
+    pub fn last_turn_angle(&self) -> u16 {
+        self.last_turn_angle
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.motors.config
+    }
+
+    // Switches to another config, e.g. of another surface. Unsaved changes of the current
+    // one are dropped, so save it first
+    pub fn set_config(&mut self, config: Config) -> Result<(), MotorsSmError> {
+        if self.busy() {
+            return Err(MotorsSmError::Busy);
+        }
+        self.motors.config = config;
+        self.motors.profile = config.drive_profile;
+        #[cfg(feature = "pid")]
+        {
+            self.left_pid = speed_pid(&config);
+            self.right_pid = speed_pid(&config);
+            self.sync_pid = sync_pid(&config);
+            self.left_turn_pid = turn_pid(&config);
+            self.right_turn_pid = turn_pid(&config);
+        }
+        // Start latencies depend on the surface too
+        self.latency_baseline = [None, None];
+        self.config_dirty = false;
+        Ok(())
+    }
+
+    pub fn config_dirty(&self) -> bool {
+        self.config_dirty
+    }
+
+    pub fn config_saved(&mut self) {
+        self.config_dirty = false;
+    }
+
+    // Speed controller gains, scaled by 256. Zero without the pid feature
+    pub fn pid_gains(&self) -> (i32, i32, i32) {
+        #[cfg(feature = "pid")]
+        let gains = (
+            self.motors.config.pid_kp,
+            self.motors.config.pid_ki,
+            self.motors.config.pid_kd,
+        );
+        #[cfg(not(feature = "pid"))]
+        let gains = (0, 0, 0);
+        gains
+    }
+
+    pub fn calibration(&self) -> Option<MotorCalibration> {
+        self.motors.config.calibration
+    }
+
+    pub fn paused(&self) -> bool {
+        matches!(self.state, MotorSmState::Paused)
+    }
+
+    // What is left of the paused move, it is started on Resume
+    pub fn paused_cmd(&self) -> Option<MotorsSmCommand> {
+        self.paused_cmd
+    }
+
+    // What was left of the last cancelled move, None if it was finished anyway
+    pub fn last_cancelled(&self) -> Option<MotorsSmCommand> {
+        self.cancelled_cmd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::CURVE_POINTS;
+    use crate::driver::{DriverCall, MockDriver};
+    use embassy_time::Duration;
+    use std::sync::{Mutex, MutexGuard};
+
+    // MotorsSm reads the global encoder counters and clock, so tests run one at a time
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    fn motors_sm() -> (MutexGuard<'static, ()>, MotorsSm<MockDriver>) {
+        motors_sm_with(Config::default())
+    }
+
+    fn motors_sm_with(config: Config) -> (MutexGuard<'static, ()>, MotorsSm<MockDriver>) {
+        let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        MOTOR1_PULSES.store(0, Ordering::Relaxed);
+        MOTOR2_PULSES.store(0, Ordering::Relaxed);
+        let motors = Motors::init(MockDriver::new(), config);
+        (guard, MotorsSm::init(motors))
+    }
+
+    fn advance(ms: u64) {
+        embassy_time::MockDriver::get().advance(Duration::from_millis(ms));
+    }
+
+    // Processes for `ms` of mock time or until MotorsSm has nothing to do
+    fn run_for(sm: &mut MotorsSm<MockDriver>, ms: u64) {
+        let mut elapsed = 0;
+        while elapsed < ms {
+            let delay = sm.process();
+            if delay == 0 {
+                break;
+            }
+            advance(delay);
+            elapsed += delay;
+        }
+    }
+
+    // Processes until a move ends, up to 10s of mock time
+    fn run_to_event(sm: &mut MotorsSm<MockDriver>) -> Option<MotionEvent> {
+        for _ in 0..10_000 / CONTROL_PERIOD {
+            let delay = sm.process();
+            if let Some(event) = sm.take_event() {
+                return Some(event);
+            }
+            advance(delay.max(CONTROL_PERIOD));
+        }
+        None
+    }
+
+    #[test]
+    fn move_completes_and_starts_queued() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(300)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Wait(100)).unwrap();
+        assert_eq!(sm.pending_len(), 1);
+
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+        assert!(matches!(event.cmd, MotorsSmCommand::Forward(300)));
+        assert!(event.duration >= 300);
+        assert_eq!(sm.pending_len(), 0);
+
+        let event = run_to_event(&mut sm).unwrap();
+        assert!(matches!(event.cmd, MotorsSmCommand::Wait(100)));
+        run_for(&mut sm, 1000);
+        assert!(!sm.busy());
+    }
+
+    #[test]
+    fn stop_cancels_move_and_keeps_remainder() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(1000)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Wait(100)).unwrap();
+        run_for(&mut sm, 200);
+        sm.process_cmd(MotorsSmCommand::Stop(StopMode::Coast))
+            .unwrap();
+
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Cancelled);
+        match sm.last_cancelled() {
+            Some(MotorsSmCommand::Forward(left)) => assert!(left > 0 && left < 1000),
+            other => panic!("unexpected remainder {:?}", other),
+        }
+        // Queue goes on after a cancelled move
+        let event = run_to_event(&mut sm).unwrap();
+        assert!(matches!(event.cmd, MotorsSmCommand::Wait(100)));
+    }
+
+    #[test]
+    fn pause_holds_queue_until_resume() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(1000)).unwrap();
+        run_for(&mut sm, 200);
+        sm.process_cmd(MotorsSmCommand::Pause).unwrap();
+        sm.process_cmd(MotorsSmCommand::Wait(100)).unwrap();
+
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Paused);
+        assert!(sm.paused());
+        assert!(matches!(
+            sm.paused_cmd(),
+            Some(MotorsSmCommand::Forward(left)) if left > 0 && left < 1000
+        ));
+        // Nothing runs while paused
+        run_for(&mut sm, 1000);
+        assert!(sm.take_event().is_none());
+        assert_eq!(sm.pending_len(), 1);
+        assert!(matches!(sm.process_cmd(MotorsSmCommand::Resume), Ok(())));
+
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+        assert!(matches!(event.cmd, MotorsSmCommand::Forward(_)));
+        let event = run_to_event(&mut sm).unwrap();
+        assert!(matches!(event.cmd, MotorsSmCommand::Wait(100)));
+        assert!(matches!(
+            sm.process_cmd(MotorsSmCommand::Resume),
+            Err(MotorsSmError::NotPaused)
+        ));
+    }
+
+    #[test]
+    fn pause_while_decelerating_completes_on_resume() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(200)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Wait(100)).unwrap();
+        while !matches!(sm.state, MotorSmState::WaitDecel) {
+            advance(sm.process().max(1));
+        }
+        sm.process_cmd(MotorsSmCommand::Pause).unwrap();
+
+        // Nothing is left of the move, its event waits for Resume
+        run_for(&mut sm, 1000);
+        assert!(sm.paused());
+        assert!(sm.paused_cmd().is_none());
+        assert!(sm.take_event().is_none());
+        sm.process_cmd(MotorsSmCommand::Resume).unwrap();
+        let event = sm.take_event().unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+        assert!(matches!(event.cmd, MotorsSmCommand::Forward(200)));
+        let event = run_to_event(&mut sm).unwrap();
+        assert!(matches!(event.cmd, MotorsSmCommand::Wait(100)));
+    }
+
+    #[test]
+    fn stop_and_pause_act_on_move_not_started_yet() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(300)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Wait(100)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Stop(StopMode::Coast))
+            .unwrap();
+        let event = sm.take_event().unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Cancelled);
+        assert!(matches!(event.cmd, MotorsSmCommand::Forward(300)));
+        assert_eq!(sm.pending_len(), 0);
+        let event = run_to_event(&mut sm).unwrap();
+        assert!(matches!(event.cmd, MotorsSmCommand::Wait(100)));
+
+        sm.process_cmd(MotorsSmCommand::Forward(300)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Pause).unwrap();
+        assert!(sm.paused());
+        assert!(matches!(
+            sm.paused_cmd(),
+            Some(MotorsSmCommand::Forward(300))
+        ));
+        run_for(&mut sm, 500);
+        assert!(sm.take_event().is_none());
+        sm.process_cmd(MotorsSmCommand::Resume).unwrap();
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+        assert!(matches!(event.cmd, MotorsSmCommand::Forward(300)));
+    }
+
+    #[test]
+    fn stop_and_pause_during_brake_hold() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(1000)).unwrap();
+        run_for(&mut sm, 200);
+        sm.process_cmd(MotorsSmCommand::EmergencyStop).unwrap();
+        run_to_event(&mut sm).unwrap();
+        assert!(matches!(sm.state, MotorSmState::Braking));
+
+        // Stop cancels the move that would start once the brake is released
+        sm.process_cmd(MotorsSmCommand::Forward(300)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Stop(StopMode::Coast))
+            .unwrap();
+        let event = sm.take_event().unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Cancelled);
+        assert_eq!(sm.pending_len(), 0);
+
+        // Pause holds the queue after the brake is released
+        sm.process_cmd(MotorsSmCommand::Forward(300)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Pause).unwrap();
+        assert_eq!(sm.pending_len(), 1);
+        run_for(&mut sm, 1000);
+        assert!(sm.paused());
+        assert!(sm.take_event().is_none());
+        sm.process_cmd(MotorsSmCommand::Resume).unwrap();
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+        assert!(matches!(event.cmd, MotorsSmCommand::Forward(300)));
+    }
+
+    #[test]
+    fn emergency_stop_drops_queue() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(1000)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Forward(500)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Wait(100)).unwrap();
+        run_for(&mut sm, 200);
+        sm.process_cmd(MotorsSmCommand::EmergencyStop).unwrap();
+
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Aborted);
+        assert_eq!(sm.pending_len(), 0);
+        // Brakes, then releases the motors after the hold time
+        assert_eq!(
+            sm.motors.driver.last(Side::Left),
+            Some(DriverCall::Brake(Side::Left))
+        );
+        run_for(&mut sm, 1000);
+        assert!(!sm.busy());
+        assert!(sm.take_event().is_none());
+        assert_eq!(
+            sm.motors.driver.last(Side::Left),
+            Some(DriverCall::Coast(Side::Left))
+        );
+    }
+
+    #[test]
+    fn emergency_stop_while_braking() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(1000)).unwrap();
+        run_for(&mut sm, 200);
+        sm.process_cmd(MotorsSmCommand::EmergencyStop).unwrap();
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Aborted);
+        assert!(matches!(sm.state, MotorSmState::Braking));
+
+        sm.process_cmd(MotorsSmCommand::Forward(500)).unwrap();
+        sm.process_cmd(MotorsSmCommand::EmergencyStop).unwrap();
+        sm.motors.driver.clear();
+        run_for(&mut sm, 1000);
+        assert!(!sm.busy());
+        assert!(sm.take_event().is_none());
+        assert_eq!(sm.pending_len(), 0);
+        // Motors are released and nothing is started
+        assert!(
+            sm.motors
+                .driver
+                .calls()
+                .all(|c| matches!(c, DriverCall::Coast(_)))
+        );
+    }
+
+    #[test]
+    fn emergency_stop_while_decelerating() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(200)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Forward(500)).unwrap();
+        while !matches!(sm.state, MotorSmState::WaitDecel) {
+            advance(sm.process().max(1));
+        }
+        sm.process_cmd(MotorsSmCommand::EmergencyStop).unwrap();
+
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Aborted);
+        assert!(matches!(event.cmd, MotorsSmCommand::Forward(200)));
+        assert_eq!(sm.pending_len(), 0);
+        run_for(&mut sm, 1000);
+        assert!(sm.take_event().is_none());
+        assert!(!sm.busy());
+    }
+
+    #[test]
+    fn emergency_stop_while_paused() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(1000)).unwrap();
+        run_for(&mut sm, 200);
+        sm.process_cmd(MotorsSmCommand::Pause).unwrap();
+        sm.process_cmd(MotorsSmCommand::Wait(100)).unwrap();
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Paused);
+
+        sm.process_cmd(MotorsSmCommand::EmergencyStop).unwrap();
+        assert!(!sm.paused());
+        assert!(sm.paused_cmd().is_none());
+        assert_eq!(sm.pending_len(), 0);
+        assert!(matches!(
+            sm.process_cmd(MotorsSmCommand::Resume),
+            Err(MotorsSmError::NotPaused)
+        ));
+        run_for(&mut sm, 1000);
+        assert!(sm.take_event().is_none());
+        assert!(!sm.busy());
+    }
+
+    // Wheels spinning at `gain` percent of their duty in pulses/s, following it with a 100ms lag
+    struct Wheels {
+        gain: [u32; 2],
+        speeds: [u32; 2],
+        // Pulses counted so far, in 1/1000
+        counts: [u32; 2],
+    }
+
+    impl Wheels {
+        fn new(gain: [u32; 2]) -> Self {
+            Self {
+                gain,
+                speeds: [0; 2],
+                counts: [0; 2],
+            }
+        }
+
+        fn update(&mut self, sm: &MotorsSm<MockDriver>, dt: u64) {
+            let duties = [sm.motors.left.duty, sm.motors.right.duty];
+            for (i, pulses) in [&MOTOR1_PULSES, &MOTOR2_PULSES].into_iter().enumerate() {
+                let target = duties[i] as u32 * self.gain[i] / 100;
+                let speed = self.speeds[i] as i64;
+                self.speeds[i] = (speed + (target as i64 - speed) * dt as i64 / 100) as u32;
+                self.counts[i] += self.speeds[i] * dt as u32;
+                pulses.fetch_add(self.counts[i] / 1000, Ordering::Relaxed);
+                self.counts[i] %= 1000;
+            }
+        }
+    }
+
+    // Processes with wheels driven by the duty, until a move ends or `ms` of mock time passed
+    fn run_driven(
+        sm: &mut MotorsSm<MockDriver>,
+        wheels: &mut Wheels,
+        ms: u64,
+    ) -> Option<MotionEvent> {
+        let mut elapsed = 0;
+        while elapsed < ms {
+            let delay = sm.process().max(CONTROL_PERIOD);
+            if let Some(event) = sm.take_event() {
+                return Some(event);
+            }
+            let mut left = delay;
+            while left > 0 {
+                let dt = left.min(CONTROL_PERIOD);
+                wheels.update(sm, dt);
+                advance(dt);
+                left -= dt;
+            }
+            elapsed += delay;
+        }
+        None
+    }
+
+    fn assert_near(value: u32, expected: u32, margin: u32) {
+        assert!(
+            value.abs_diff(expected) <= margin,
+            "{} is not within {} of {}",
+            value,
+            margin,
+            expected
+        );
+    }
+
+    #[test]
+    fn distance_move_stops_at_target() {
+        for (cmd, direction) in [
+            (
+                MotorsSmCommand::ForwardDistance(200, 100),
+                Direction::Forward,
+            ),
+            (
+                MotorsSmCommand::BackwardsDistance(200, 100),
+                Direction::Backwards,
+            ),
+        ] {
+            let (_lock, mut sm) = motors_sm();
+            sm.process_cmd(cmd).unwrap();
+            let event = run_driven(&mut sm, &mut Wheels::new([100, 100]), 10_000).unwrap();
+            assert_eq!(event.outcome, MotionOutcome::Complete);
+            let target = sm.config().mm_to_pulses(200);
+            assert_near(event.left_pulses, target, sm.config().decel_pulses);
+            assert_near(event.right_pulses, target, sm.config().decel_pulses);
+            assert!(sm.motors.driver.calls().any(
+                |c| matches!(c, DriverCall::Set(Side::Left, d, duty) if *d == direction && *duty > 0)
+            ));
+        }
+    }
+
+    #[test]
+    fn turn_stops_each_wheel_at_its_target() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::TurnLeft(90, 100)).unwrap();
+        // Left wheel is much faster, it is stopped while the right one keeps going
+        let mut wheels = Wheels::new([150, 75]);
+        let target = sm.config().deg_to_pulses(90);
+        let decel = sm.config().decel_pulses;
+        let mut stopped_at = (None, None);
+        let event = loop {
+            let delay = sm.process().max(CONTROL_PERIOD);
+            if let Some(event) = sm.take_event() {
+                break event;
+            }
+            let pulses = (
+                MOTOR1_PULSES.load(Ordering::Relaxed),
+                MOTOR2_PULSES.load(Ordering::Relaxed),
+            );
+            if sm.left_stopped && stopped_at.0.is_none() {
+                stopped_at.0 = Some(pulses);
+            }
+            if sm.right_stopped && stopped_at.1.is_none() {
+                stopped_at.1 = Some(pulses);
+            }
+            wheels.update(&sm, delay);
+            advance(delay);
+        };
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+        let (Some(left_stop), Some(right_stop)) = stopped_at else {
+            panic!("wheels not stopped separately: {:?}", stopped_at);
+        };
+        // Each wheel is stopped once it gets within the decel pulses of its target
+        assert!(left_stop.0 + decel >= target && left_stop.0 < target);
+        assert!(left_stop.1 + decel < target);
+        assert!(right_stop.1 + decel >= target && right_stop.1 < target);
+    }
+
+    #[test]
+    fn arc_drives_wheels_along_their_circles() {
+        let (_lock, mut sm) = motors_sm();
+        // Radii are 160mm and 240mm, so the wheels travel 240mm and 360mm
+        sm.process_cmd(MotorsSmCommand::Arc {
+            radius_mm: 200,
+            length_mm: 300,
+            speed: 100,
+        })
+        .unwrap();
+        let event = run_driven(&mut sm, &mut Wheels::new([100, 100]), 10_000).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+        assert_eq!(sm.speeds, (66, 100));
+        let decel = sm.config().decel_pulses;
+        assert_near(event.left_pulses, sm.config().mm_to_pulses(240), decel);
+        assert_near(event.right_pulses, sm.config().mm_to_pulses(360), decel);
+    }
+
+    #[test]
+    fn stalled_move_ends_after_timeout() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::ForwardDistance(500, 100))
+            .unwrap();
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+        assert_eq!(event.left_pulses, 0);
+        let stall_timeout = sm.config().stall_timeout;
+        assert!(event.duration > stall_timeout && event.duration < 2 * stall_timeout + 500);
+    }
+
+    #[test]
+    fn brake_stop_holds_then_coasts() {
+        let config = Config {
+            stop_mode: StopMode::Brake,
+            ..Config::default()
+        };
+        let (_lock, mut sm) = motors_sm_with(config);
+        sm.process_cmd(MotorsSmCommand::Forward(300)).unwrap();
+        let mut delay = sm.process();
+        while !matches!(sm.state, MotorSmState::WaitDecel) {
+            advance(delay);
+            delay = sm.process();
+        }
+        assert_eq!(delay, config.brake_hold_time as u64);
+        assert_eq!(
+            sm.motors.driver.last(Side::Left),
+            Some(DriverCall::Brake(Side::Left))
+        );
+        assert_eq!(
+            sm.motors.driver.last(Side::Right),
+            Some(DriverCall::Brake(Side::Right))
+        );
+        assert!(sm.take_event().is_none());
+
+        advance(delay);
+        sm.process();
+        let event = sm.take_event().unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+        assert_eq!(
+            sm.motors.driver.last(Side::Left),
+            Some(DriverCall::Coast(Side::Left))
+        );
+    }
+
+    #[test]
+    fn calibration_measures_speed_curves() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Calibrate).unwrap();
+        let event = run_driven(&mut sm, &mut Wheels::new([100, 50]), 60_000).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+        assert!(matches!(event.cmd, MotorsSmCommand::Calibrate));
+        assert!(sm.config_dirty());
+        let calibration = sm.calibration().unwrap();
+        // Wheels settle to their speed before it is measured
+        let left = calibration.curve(Side::Left, Direction::Forward);
+        let right = calibration.curve(Side::Right, Direction::Backwards);
+        assert_eq!(left.dead_band, 10);
+        assert_near(left.speeds[CURVE_POINTS - 1] as u32, 100, 5);
+        assert_near(right.speeds[CURVE_POINTS - 1] as u32, 50, 5);
+    }
+
+    #[cfg(feature = "pid")]
+    #[test]
+    fn autotune_sets_speed_gains() {
+        let (_lock, mut sm) = motors_sm();
+        let gains = sm.pid_gains();
+        sm.process_cmd(MotorsSmCommand::AutoTune).unwrap();
+        // Relay swings the duty 15% around 70%, the speed crosses the target of 80 pulses/s
+        let event = run_driven(&mut sm, &mut Wheels::new([115, 115]), 30_000).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+        assert!(matches!(event.cmd, MotorsSmCommand::AutoTune));
+        assert_ne!(sm.pid_gains(), gains);
+        assert!(sm.config_dirty());
+    }
+
+    #[cfg(feature = "pid")]
+    #[test]
+    fn duty_is_learned_only_where_it_settled() {
+        let config = Config {
+            min_duty: 40,
+            max_duty: 85,
+            ..Config::default()
+        };
+
+        // Wheels reach the target of 80 pulses/s at a duty of 80%
+        let (lock, mut sm) = motors_sm_with(config);
+        sm.process_cmd(MotorsSmCommand::Forward(3000)).unwrap();
+        run_driven(&mut sm, &mut Wheels::new([100, 100]), 10_000).unwrap();
+        assert!(sm.config_dirty());
+        assert_near(sm.config().left_duty as u32, 80, 3);
+        assert_near(sm.config().right_duty as u32, 80, 3);
+        drop(lock);
+
+        // Duty found at 60% speed isn't the one for the nominal speed
+        let (lock, mut sm) = motors_sm_with(config);
+        sm.process_cmd(MotorsSmCommand::ForwardDistance(1000, 60))
+            .unwrap();
+        run_driven(&mut sm, &mut Wheels::new([100, 100]), 30_000).unwrap();
+        assert!(!sm.config_dirty());
+        drop(lock);
+
+        // Weak wheels never reach the target, the duty stays at its limit
+        let (_lock, mut sm) = motors_sm_with(config);
+        sm.process_cmd(MotorsSmCommand::Forward(3000)).unwrap();
+        run_driven(&mut sm, &mut Wheels::new([50, 50]), 10_000).unwrap();
+        assert!(!sm.config_dirty());
+    }
+
+    #[test]
+    fn stop_while_accelerating() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(1000)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Wait(100)).unwrap();
+        advance(sm.process());
+        assert!(matches!(sm.state, MotorSmState::WaitAccel));
+        sm.process_cmd(MotorsSmCommand::Stop(StopMode::Coast))
+            .unwrap();
+        // Stop is not queued behind the move
+        assert_eq!(sm.pending_len(), 1);
+
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Cancelled);
+        assert!(event.duration < 300);
+        assert!(matches!(
+            sm.last_cancelled(),
+            Some(MotorsSmCommand::Forward(left)) if left > 800
+        ));
+        assert_eq!(sm.current_duties(), (0, 0));
+    }
+
+    // Both wheels count a pulse every step once `lag` steps of the move have passed
+    fn run_counting(sm: &mut MotorsSm<MockDriver>, lag: u32) -> MotionEvent {
+        for step in 0..10_000 / CONTROL_PERIOD as u32 {
+            let delay = sm.process();
+            if let Some(event) = sm.take_event() {
+                return event;
+            }
+            if step >= lag {
+                MOTOR1_PULSES.fetch_add(1, Ordering::Relaxed);
+                MOTOR2_PULSES.fetch_add(1, Ordering::Relaxed);
+            }
+            advance(delay.max(CONTROL_PERIOD));
+        }
+        panic!("move didn't end");
+    }
+
+    #[test]
+    fn backlash_time_extends_timed_moves() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(200)).unwrap();
+        run_counting(&mut sm, 0);
+        sm.config_saved();
+        assert_eq!(sm.config().backlash_time, 0);
+
+        // Reversing wheels take up the backlash before they count
+        sm.process_cmd(MotorsSmCommand::Backwards(200)).unwrap();
+        run_counting(&mut sm, 7);
+        let backlash_time = sm.config().backlash_time;
+        assert!(backlash_time > 0);
+        assert!(sm.config_dirty());
+
+        sm.process_cmd(MotorsSmCommand::Forward(200)).unwrap();
+        while !matches!(sm.state, MotorSmState::Forward) {
+            advance(sm.process().max(1));
+        }
+        assert_eq!(sm.move_time, 200 + backlash_time as u64);
+    }
+
+    #[test]
+    fn distance_move_after_reversing_doesnt_overshoot() {
+        let config = Config {
+            backlash_time: 100,
+            ..Config::default()
+        };
+        let (_lock, mut sm) = motors_sm_with(config);
+        let mut wheels = Wheels::new([100, 100]);
+        sm.process_cmd(MotorsSmCommand::ForwardDistance(200, 100))
+            .unwrap();
+        run_driven(&mut sm, &mut wheels, 10_000).unwrap();
+        // Encoders count the wheels, so a reversing wheel has the same target
+        sm.process_cmd(MotorsSmCommand::BackwardsDistance(200, 100))
+            .unwrap();
+        let event = run_driven(&mut sm, &mut wheels, 10_000).unwrap();
+        assert!(matches!(event.cmd, MotorsSmCommand::BackwardsDistance(..)));
+        let target = sm.config().mm_to_pulses(200);
+        assert_eq!(sm.targets, (target, target));
+        assert_near(event.left_pulses, target, sm.config().decel_pulses);
+        assert_near(event.right_pulses, target, sm.config().decel_pulses);
+    }
+
+    #[test]
+    fn driver_error_faults_until_cleared() {
+        let (_lock, mut sm) = motors_sm();
+        sm.process_cmd(MotorsSmCommand::Forward(1000)).unwrap();
+        sm.process_cmd(MotorsSmCommand::Wait(100)).unwrap();
+        run_for(&mut sm, 100);
+        sm.motors.driver.set_fault(Some(DriverError::Mock));
+
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Aborted);
+        assert_eq!(sm.fault(), Some(DriverError::Mock));
+        assert_eq!(sm.pending_len(), 0);
+        assert!(matches!(
+            sm.process_cmd(MotorsSmCommand::Forward(100)),
+            Err(MotorsSmError::Driver(DriverError::Mock))
+        ));
+        assert!(sm.clear_fault().is_err());
+
+        assert!(sm.motors.driver.calls().any(|c| *c == DriverCall::Disable));
+        sm.motors.driver.set_fault(None);
+        sm.motors.driver.clear();
+        sm.clear_fault().unwrap();
+        assert_eq!(sm.fault(), None);
+        assert_eq!(sm.motors.driver.calls().next(), Some(&DriverCall::Enable));
+        sm.process_cmd(MotorsSmCommand::Forward(100)).unwrap();
+        let event = run_to_event(&mut sm).unwrap();
+        assert_eq!(event.outcome, MotionOutcome::Complete);
+    }
 }
@@ -7,9 +7,23 @@ pub struct Pid {
     kp: i32,
     ki: i32,
     kd: i32,
-    integral: i32,
+    // Integral term, already multiplied by ki, so changing ki doesn't make the output jump
+    i_term: i32,
+    // Limit of the accumulated error
     integral_limit: i32,
-    prev_error: i32,
+    output_min: i32,
+    output_max: i32,
+    // How much of the saturation excess is taken back out of the integral (back-calculation)
+    anti_windup: i32,
+    // Weight of the setpoint in the proportional term. Less than SCALE softens
+    // the response to setpoint steps, while disturbances are still fully corrected
+    setpoint_weight: i32,
+    // Low-pass factor of the derivative, SCALE means no filtering
+    derivative_filter: i32,
+    derivative: i32,
+    prev_measurement: Option<i32>,
+    // Last proportional error, kept for bumpless gain changes
+    p_error: i32,
 }
 
 impl Pid {
@@ -18,22 +32,269 @@ impl Pid {
             kp,
             ki,
             kd,
-            integral: 0,
+            i_term: 0,
             integral_limit,
-            prev_error: 0,
+            output_min: i32::MIN,
+            output_max: i32::MAX,
+            anti_windup: SCALE,
+            setpoint_weight: SCALE,
+            derivative_filter: SCALE,
+            derivative: 0,
+            prev_measurement: None,
+            p_error: 0,
         }
     }
 
+    pub const fn with_output_limits(mut self, min: i32, max: i32) -> Self {
+        self.output_min = min;
+        self.output_max = max;
+        self
+    }
+
+    pub const fn with_anti_windup(mut self, gain: i32) -> Self {
+        self.anti_windup = gain;
+        self
+    }
+
+    pub const fn with_setpoint_weight(mut self, weight: i32) -> Self {
+        self.setpoint_weight = weight;
+        self
+    }
+
+    pub const fn with_derivative_filter(mut self, filter: i32) -> Self {
+        self.derivative_filter = filter;
+        self
+    }
+
+    // Limits can change between updates, e.g. when they depend on the commanded speed
+    pub fn set_output_limits(&mut self, min: i32, max: i32) {
+        self.output_min = min;
+        self.output_max = max;
+    }
+
+    // Bumpless transfer: the integral absorbs the change of the P and D terms,
+    // so the output doesn't jump when the gains change
+    pub fn set_gains(&mut self, kp: i32, ki: i32, kd: i32) {
+        self.i_term += (self.kp - kp) * self.p_error + (self.kd - kd) * self.derivative;
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+        self.clamp_integral();
+    }
+
+    pub fn gains(&self) -> (i32, i32, i32) {
+        (self.kp, self.ki, self.kd)
+    }
+
+    fn clamp_integral(&mut self) {
+        let limit = self.ki.abs().max(1) * self.integral_limit;
+        self.i_term = self.i_term.clamp(-limit, limit);
+    }
+
     pub fn update(&mut self, setpoint: i32, measurement: i32) -> i32 {
+        self.update_with_feed_forward(setpoint, measurement, 0)
+    }
+
+    // Feed-forward is added to the output as is, before saturation
+    pub fn update_with_feed_forward(
+        &mut self,
+        setpoint: i32,
+        measurement: i32,
+        feed_forward: i32,
+    ) -> i32 {
         let error = setpoint - measurement;
-        self.integral = (self.integral + error).clamp(-self.integral_limit, self.integral_limit);
-        let derivative = error - self.prev_error;
-        self.prev_error = error;
-        (self.kp * error + self.ki * self.integral + self.kd * derivative) / SCALE
+        self.p_error = setpoint * self.setpoint_weight / SCALE - measurement;
+
+        // Derivative on measurement, so setpoint steps don't kick the output.
+        // Nothing to compare the first measurement against
+        let raw_derivative = match self.prev_measurement {
+            Some(prev) => prev - measurement,
+            None => 0,
+        };
+        self.prev_measurement = Some(measurement);
+        self.derivative += (raw_derivative - self.derivative) * self.derivative_filter / SCALE;
+
+        self.i_term += self.ki * error;
+        self.clamp_integral();
+
+        let output = (self.kp * self.p_error + self.i_term + self.kd * self.derivative) / SCALE
+            + feed_forward;
+        let saturated = output.clamp(self.output_min, self.output_max);
+        // P and PD controllers have no integral to unwind
+        if saturated != output && self.ki != 0 {
+            // Back-calculation keeps the integral from winding up while the output is limited
+            self.i_term += (saturated - output) * self.anti_windup;
+            self.clamp_integral();
+        }
+        saturated
     }
 
     pub fn reset(&mut self) {
-        self.integral = 0;
-        self.prev_error = 0;
+        self.i_term = 0;
+        self.derivative = 0;
+        self.prev_measurement = None;
+        self.p_error = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // First-order plant, e.g. a wheel whose speed follows the duty: the measurement approaches
+    // `gain` times the output with a time constant of `tau` samples. Plant state is kept in
+    // 1/100, so small changes aren't rounded away
+    struct Plant {
+        gain: i32,
+        tau: i32,
+        state: i32,
+    }
+
+    impl Plant {
+        fn new(gain: i32, tau: i32) -> Self {
+            Self {
+                gain,
+                tau,
+                state: 0,
+            }
+        }
+
+        fn measurement(&self) -> i32 {
+            self.state / 100
+        }
+
+        fn step(&mut self, output: i32) {
+            self.state += (self.gain * output * 100 - self.state) / self.tau;
+        }
+    }
+
+    // Runs `samples` updates towards `setpoint`. Returns the measurements after each one
+    fn run(pid: &mut Pid, plant: &mut Plant, setpoint: i32, samples: usize) -> Vec<i32> {
+        (0..samples)
+            .map(|_| {
+                let output = pid.update(setpoint, plant.measurement());
+                plant.step(output);
+                plant.measurement()
+            })
+            .collect()
+    }
+
+    // First sample from which the response stays within `band` of `target`
+    fn settled_at(response: &[i32], target: i32, band: i32) -> Option<usize> {
+        let last_out = response.iter().rposition(|&y| (y - target).abs() > band);
+        match last_out {
+            Some(i) if i + 1 == response.len() => None,
+            Some(i) => Some(i + 1),
+            None => Some(0),
+        }
+    }
+
+    #[test]
+    fn step_response_settles_without_steady_state_error() {
+        // (kp, ki, highest measurement, samples until within 2%)
+        for (kp, ki, max, settled) in [(SCALE, SCALE / 4, 100, 13), (SCALE, SCALE / 2, 109, 13)] {
+            let mut pid = Pid::new(kp, ki, 0, 10_000).with_output_limits(0, 255);
+            let mut plant = Plant::new(1, 5);
+            let response = run(&mut pid, &mut plant, 100, 60);
+            assert_eq!(response.iter().max(), Some(&max), "kp={} ki={}", kp, ki);
+            assert_eq!(
+                settled_at(&response, 100, 2),
+                Some(settled),
+                "kp={} ki={}",
+                kp,
+                ki
+            );
+            assert_eq!(response.last(), Some(&100));
+        }
+    }
+
+    #[test]
+    fn recovers_from_saturation() {
+        // Setpoint out of reach saturates the output, then a reachable one is set
+        let saturate = |pid: &mut Pid| {
+            let mut plant = Plant::new(1, 5);
+            let response = run(pid, &mut plant, 100, 50);
+            assert_eq!(response.last(), Some(&59));
+            run(pid, &mut plant, 40, 80)
+        };
+        let mut pid = Pid::new(SCALE / 2, SCALE / 8, 0, 10_000).with_output_limits(0, 60);
+        let response = saturate(&mut pid);
+        assert_eq!(settled_at(&response, 40, 2), Some(16));
+        assert!(response.iter().all(|&y| y >= 36));
+
+        // Wound-up integral keeps the output saturated long after the setpoint dropped
+        let mut wound = Pid::new(SCALE / 2, SCALE / 8, 0, 10_000)
+            .with_output_limits(0, 60)
+            .with_anti_windup(0);
+        let response = saturate(&mut wound);
+        assert!(response.iter().all(|&y| y == 59));
+    }
+
+    #[test]
+    fn output_saturates() {
+        let mut pid = Pid::new(SCALE, 0, 0, 1000).with_output_limits(-10, 10);
+        assert_eq!(pid.update(100, 0), 10);
+        assert_eq!(pid.update(-100, 0), -10);
+        assert_eq!(pid.update(5, 0), 5);
+    }
+
+    #[test]
+    fn back_calculation_stops_windup() {
+        let mut pid = Pid::new(0, SCALE, 0, 1000).with_output_limits(-10, 10);
+        let mut wound = Pid::new(0, SCALE, 0, 1000)
+            .with_output_limits(-10, 10)
+            .with_anti_windup(0);
+        for _ in 0..10 {
+            assert_eq!(pid.update(50, 0), 10);
+            assert_eq!(wound.update(50, 0), 10);
+        }
+        // Integral only holds the saturated output, so it leaves saturation at once
+        assert_eq!(pid.update(0, 5), 5);
+        assert_eq!(wound.update(0, 5), 10);
+    }
+
+    #[test]
+    fn setpoint_step_doesnt_kick_derivative() {
+        let mut pid = Pid::new(0, 0, SCALE, 1000);
+        assert_eq!(pid.update(0, 10), 0);
+        assert_eq!(pid.update(100, 10), 0);
+        assert_eq!(pid.update(100, 20), -10);
+    }
+
+    #[test]
+    fn derivative_is_filtered() {
+        let mut pid = Pid::new(0, 0, SCALE, 1000).with_derivative_filter(SCALE / 2);
+        assert_eq!(pid.update(0, 0), 0);
+        assert_eq!(pid.update(0, -10), 5);
+        assert_eq!(pid.update(0, -10), 3);
+    }
+
+    #[test]
+    fn feed_forward_is_added_before_saturation() {
+        let mut pid = Pid::new(SCALE, 0, 0, 1000);
+        assert_eq!(pid.update_with_feed_forward(10, 0, 20), 30);
+        pid.set_output_limits(-25, 25);
+        assert_eq!(pid.update_with_feed_forward(10, 0, 20), 25);
+        assert_eq!(pid.update_with_feed_forward(0, 0, -40), -25);
+    }
+
+    #[test]
+    fn setpoint_weight_softens_proportional_term() {
+        let mut pid = Pid::new(SCALE, 0, 0, 1000).with_setpoint_weight(SCALE / 2);
+        assert_eq!(pid.update(100, 0), 50);
+        // Disturbance of the measurement is fully corrected
+        assert_eq!(pid.update(0, -10), 10);
+    }
+
+    #[test]
+    fn gain_change_is_bumpless() {
+        let mut pid = Pid::new(SCALE, SCALE / 4, 0, 1000);
+        let mut same = Pid::new(SCALE, SCALE / 4, 0, 1000);
+        assert_eq!(pid.update(10, 0), same.update(10, 0));
+        pid.set_gains(2 * SCALE, SCALE / 4, 0);
+        assert_eq!(pid.gains(), (2 * SCALE, SCALE / 4, 0));
+        // Same error, so only the integral moves on. Doubled kp alone would give 25
+        assert_eq!(pid.update(10, 0), same.update(10, 0));
+        assert_eq!(pid.update(10, 0), 17);
     }
 }
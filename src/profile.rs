@@ -0,0 +1,68 @@
+use embassy_time::Instant;
+
+#[derive(Debug, Copy, Clone)]
+pub enum ProfileKind {
+    // Constant acceleration, duty changes linearly
+    Trapezoidal,
+    // Acceleration ramps up and down (smoothstep), gentler on the wheels at start and end
+    SCurve,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Profile {
+    pub kind: ProfileKind,
+    pub accel_time: u16, // ms
+    pub decel_time: u16, // ms
+}
+
+impl ProfileKind {
+    // Part of the ramp done after `t` out of `time`, in 1/1000
+    pub fn progress(self, t: u64, time: u64) -> u64 {
+        if t >= time {
+            return 1000;
+        }
+        let x = t * 1000 / time;
+        match self {
+            ProfileKind::Trapezoidal => x,
+            ProfileKind::SCurve => x * x * (3000 - 2 * x) / 1_000_000,
+        }
+    }
+}
+
+// Duty ramp of a single wheel
+#[derive(Debug, Copy, Clone)]
+pub struct Ramp {
+    kind: ProfileKind,
+    from: u8,
+    to: u8,
+    time: u16,
+    start: Instant,
+}
+
+impl Ramp {
+    pub fn new(kind: ProfileKind, from: u8, to: u8, time: u16) -> Self {
+        Self {
+            kind,
+            from,
+            to,
+            time,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn target(&self) -> u8 {
+        self.to
+    }
+
+    pub fn accelerating(&self) -> bool {
+        self.to > self.from
+    }
+
+    // Duty at the current time, and whether the ramp is finished
+    pub fn duty(&self) -> (u8, bool) {
+        let t = self.start.elapsed().as_millis();
+        let progress = self.kind.progress(t, self.time as u64) as i32;
+        let duty = self.from as i32 + (self.to as i32 - self.from as i32) * progress / 1000;
+        (duty as u8, progress == 1000)
+    }
+}
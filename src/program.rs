@@ -0,0 +1,214 @@
+use heapless::Vec;
+
+use crate::color::Color;
+use crate::motors::MotorsSmCommand;
+use crate::storage::{Slot, StorageError, Store};
+
+pub const PROGRAM_SIZE: usize = 64; // actions
+pub const TAPE_SIZE: usize = 64; // mats
+const TAPE_VERSION: u16 = 1;
+
+// What the robot did on one mat
+#[derive(Debug, Copy, Clone)]
+pub struct Action {
+    pub cmd: MotorsSmCommand,
+    // Turns and pauses leave the robot on the mat, then it moves on to the next one
+    pub move_off: Option<MotorsSmCommand>,
+}
+
+impl Action {
+    pub fn new(cmd: MotorsSmCommand) -> Self {
+        Self {
+            cmd,
+            move_off: None,
+        }
+    }
+}
+
+// Actions executed since the program started, so they can be replayed without the mats
+pub struct Program {
+    actions: Vec<Action, PROGRAM_SIZE>,
+}
+
+impl Program {
+    pub const fn new() -> Self {
+        Self {
+            actions: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.actions.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Action> {
+        self.actions.get(index)
+    }
+
+    // Returns false once the program is full, the action is not recorded then
+    pub fn push(&mut self, action: Action) -> bool {
+        self.actions.push(action).is_ok()
+    }
+
+    // Completes the last action with the move off its mat
+    pub fn set_move_off(&mut self, cmd: MotorsSmCommand) {
+        if let Some(action) = self.actions.last_mut() {
+            action.move_off = Some(cmd);
+        }
+    }
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub enum ReplayStep {
+    // First command of the next action
+    Action(Action),
+    MoveOff(MotorsSmCommand),
+}
+
+// Replays actions `start..end` of a program `times` times
+pub struct Replay {
+    start: usize,
+    end: usize,
+    times: u8,
+    index: usize,
+    move_off: bool,
+}
+
+impl Replay {
+    pub fn new(start: usize, end: usize, times: u8) -> Self {
+        Self {
+            start,
+            end,
+            times: if start < end { times } else { 0 },
+            index: start,
+            move_off: false,
+        }
+    }
+
+    pub fn next(&mut self, program: &Program) -> Option<ReplayStep> {
+        if self.times == 0 {
+            return None;
+        }
+        let action = *program.get(self.index)?;
+        if self.move_off {
+            self.move_off = false;
+            self.advance();
+            return action.move_off.map(ReplayStep::MoveOff);
+        }
+        if action.move_off.is_some() {
+            self.move_off = true;
+        } else {
+            self.advance();
+        }
+        Some(ReplayStep::Action(action))
+    }
+
+    fn advance(&mut self) {
+        self.index += 1;
+        if self.index == self.end {
+            self.index = self.start;
+            self.times -= 1;
+        }
+    }
+}
+
+// Colors of the mats the robot was pushed over in record mode, run later without the mats
+pub struct Tape {
+    colors: Vec<Color, TAPE_SIZE>,
+}
+
+impl Tape {
+    pub const fn new() -> Self {
+        Self { colors: Vec::new() }
+    }
+
+    pub fn clear(&mut self) {
+        self.colors.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Color> {
+        self.colors.get(index).copied()
+    }
+
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    // Returns false once the tape is full
+    pub fn push(&mut self, color: Color) -> bool {
+        self.colors.push(color).is_ok()
+    }
+
+    // Replaces the mat at `index`, or appends one right after the last mat
+    pub fn set(&mut self, index: usize, color: Color) -> bool {
+        if index == self.colors.len() {
+            return self.push(color);
+        }
+        match self.colors.get_mut(index) {
+            Some(c) => {
+                *c = color;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert(&mut self, index: usize, color: Color) -> bool {
+        index <= self.colors.len() && self.colors.insert(index, color).is_ok()
+    }
+
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index < self.colors.len() {
+            self.colors.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn load(store: &mut Store) -> Result<Self, StorageError> {
+        let mut buf = [0u8; TAPE_SIZE];
+        let data = store.load(Slot::Program, TAPE_VERSION, &mut buf)?;
+        let mut tape = Self::new();
+        for &v in data {
+            let color = Color::from_u8(v).ok_or(StorageError::Corrupted)?;
+            tape.push(color);
+        }
+        Ok(tape)
+    }
+
+    pub fn save(&self, store: &mut Store) -> Result<(), StorageError> {
+        let mut buf = [0u8; TAPE_SIZE];
+        for (b, c) in buf.iter_mut().zip(self.colors.iter()) {
+            *b = c.to_u8();
+        }
+        store.save(Slot::Program, TAPE_VERSION, &buf[..self.colors.len()])
+    }
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,167 @@
+use crate::color::Color;
+use crate::motors::StopMode;
+use crate::surface::Surface;
+
+// Commands sent to the robot over ESP-NOW
+pub const MAGIC: u32 = 0xC0DE_CAFE;
+pub const REVISION: u32 = 4;
+pub const PACKET_SIZE: usize = 14;
+
+#[derive(Debug, Clone, Copy)]
+pub enum RemoteCommand {
+    // Switches to the motor config of another surface once the robot is stopped
+    SelectSurface(Surface),
+    // Number of actions the repeat mat replays, and how many times
+    SetRepeat {
+        actions: u8,
+        times: u8,
+    },
+    // Starts or stops recording the mats the robot is pushed over
+    Record(bool),
+    // Runs the recorded mats
+    Run,
+    // Saves the recorded mats to flash, they are loaded on the next power-up
+    SaveProgram,
+    // Asks for a program telemetry packet
+    ViewProgram,
+    // Replaces the mat at index, or appends one right after the last mat
+    SetStep {
+        index: u8,
+        color: Color,
+    },
+    InsertStep {
+        index: u8,
+        color: Color,
+    },
+    DeleteStep {
+        index: u8,
+    },
+    // Pauses the current move, Resume finishes it
+    Pause,
+    Resume,
+    // Cancels the current or paused move, and the program driving it
+    Stop(StopMode),
+    // Drives along a circle while the robot is not running a program, see MotorsSmCommand::Arc
+    Arc {
+        radius_mm: i16,
+        length_mm: u16,
+        speed: u8,
+    },
+    // Measures the speed curves of the motors on the current surface, the robot spins in place
+    Calibrate,
+    // Finds the speed controller gains on the current surface, the robot spins in place.
+    // Ignored without the pid feature
+    AutoTune,
+    // Turns the motor driver back on after it failed, once the cause is fixed
+    ClearFault,
+}
+
+#[allow(dead_code)]
+pub fn pack(cmd: &RemoteCommand) -> [u8; PACKET_SIZE] {
+    let mut buf = [0u8; PACKET_SIZE];
+    buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    buf[4..8].copy_from_slice(&REVISION.to_le_bytes());
+    match cmd {
+        RemoteCommand::SelectSurface(surface) => {
+            buf[8] = 0;
+            buf[9] = surface.to_u8();
+        }
+        RemoteCommand::SetRepeat { actions, times } => {
+            buf[8] = 1;
+            buf[9] = *actions;
+            buf[10] = *times;
+        }
+        RemoteCommand::Record(on) => {
+            buf[8] = 2;
+            buf[9] = *on as u8;
+        }
+        RemoteCommand::Run => buf[8] = 3,
+        RemoteCommand::SaveProgram => buf[8] = 4,
+        RemoteCommand::ViewProgram => buf[8] = 5,
+        RemoteCommand::SetStep { index, color } => {
+            buf[8] = 6;
+            buf[9] = *index;
+            buf[10] = color.to_u8();
+        }
+        RemoteCommand::InsertStep { index, color } => {
+            buf[8] = 7;
+            buf[9] = *index;
+            buf[10] = color.to_u8();
+        }
+        RemoteCommand::DeleteStep { index } => {
+            buf[8] = 8;
+            buf[9] = *index;
+        }
+        RemoteCommand::Pause => buf[8] = 9,
+        RemoteCommand::Resume => buf[8] = 10,
+        RemoteCommand::Stop(mode) => {
+            buf[8] = 11;
+            buf[9] = (*mode == StopMode::Brake) as u8;
+        }
+        RemoteCommand::Arc {
+            radius_mm,
+            length_mm,
+            speed,
+        } => {
+            buf[8] = 12;
+            buf[9..11].copy_from_slice(&radius_mm.to_le_bytes());
+            buf[11..13].copy_from_slice(&length_mm.to_le_bytes());
+            buf[13] = *speed;
+        }
+        RemoteCommand::Calibrate => buf[8] = 13,
+        RemoteCommand::AutoTune => buf[8] = 14,
+        RemoteCommand::ClearFault => buf[8] = 15,
+    }
+    buf
+}
+
+#[allow(dead_code)]
+pub fn unpack(buf: &[u8]) -> Option<RemoteCommand> {
+    if buf.len() < PACKET_SIZE {
+        return None;
+    }
+    let magic = u32::from_le_bytes(buf[0..4].try_into().ok()?);
+    if magic != MAGIC {
+        return None;
+    }
+    let revision = u32::from_le_bytes(buf[4..8].try_into().ok()?);
+    if revision != REVISION {
+        return None;
+    }
+    match buf[8] {
+        0 => Some(RemoteCommand::SelectSurface(Surface::from_u8(buf[9])?)),
+        1 => Some(RemoteCommand::SetRepeat {
+            actions: buf[9],
+            times: buf[10],
+        }),
+        2 => Some(RemoteCommand::Record(buf[9] != 0)),
+        3 => Some(RemoteCommand::Run),
+        4 => Some(RemoteCommand::SaveProgram),
+        5 => Some(RemoteCommand::ViewProgram),
+        6 => Some(RemoteCommand::SetStep {
+            index: buf[9],
+            color: Color::from_u8(buf[10])?,
+        }),
+        7 => Some(RemoteCommand::InsertStep {
+            index: buf[9],
+            color: Color::from_u8(buf[10])?,
+        }),
+        8 => Some(RemoteCommand::DeleteStep { index: buf[9] }),
+        9 => Some(RemoteCommand::Pause),
+        10 => Some(RemoteCommand::Resume),
+        11 => Some(RemoteCommand::Stop(if buf[9] != 0 {
+            StopMode::Brake
+        } else {
+            StopMode::Coast
+        })),
+        12 => Some(RemoteCommand::Arc {
+            radius_mm: i16::from_le_bytes(buf[9..11].try_into().ok()?),
+            length_mm: u16::from_le_bytes(buf[11..13].try_into().ok()?),
+            speed: buf[13],
+        }),
+        13 => Some(RemoteCommand::Calibrate),
+        14 => Some(RemoteCommand::AutoTune),
+        15 => Some(RemoteCommand::ClearFault),
+        _ => None,
+    }
+}
@@ -0,0 +1,150 @@
+// Flash access only builds for the robot, host tests keep records in RAM
+#[cfg(target_arch = "riscv32")]
+mod flash;
+#[cfg(not(target_arch = "riscv32"))]
+mod ram;
+
+#[cfg(target_arch = "riscv32")]
+pub use flash::Store;
+#[cfg(not(target_arch = "riscv32"))]
+pub use ram::Store;
+
+const SECTOR_SIZE: usize = 4096;
+// magic, version, length, crc
+const HEADER_SIZE: usize = 12;
+pub const MAX_RECORD_SIZE: usize = SECTOR_SIZE - HEADER_SIZE;
+
+#[derive(Debug, Copy, Clone)]
+pub enum StorageError {
+    // Flash driver failed
+    #[cfg(target_arch = "riscv32")]
+    Flash(esp_storage::FlashStorageError),
+    OutOfBounds,
+    NoPartition,
+    // Record doesn't fit its buffer or its sector
+    TooLarge,
+    // Nothing saved yet, or saved by another firmware version
+    NotFound,
+    Corrupted,
+}
+
+// Each record takes its own sector of the storage partition
+#[derive(Debug, Copy, Clone)]
+pub enum Slot {
+    // Motor config of each surface. Table is the one older firmware saved
+    TableConfig = 0,
+    CarpetConfig = 1,
+    FoamConfig = 2,
+    // Surface selected last
+    Surface = 3,
+    // Mats recorded for a program run without them
+    Program = 4,
+}
+
+// Little-endian serialization of records
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    // Something didn't fit the buffer
+    overflow: bool,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            overflow: false,
+        }
+    }
+
+    // Bytes written so far, or TooLarge if anything didn't fit
+    pub fn data(&self) -> Result<&[u8], StorageError> {
+        if self.overflow {
+            return Err(StorageError::TooLarge);
+        }
+        Ok(&self.buf[..self.pos])
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        match self.buf.get_mut(self.pos..self.pos + bytes.len()) {
+            Some(dest) => {
+                dest.copy_from_slice(bytes);
+                self.pos += bytes.len();
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.bytes(&[v]);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, v: i32) {
+        self.bytes(&v.to_le_bytes());
+    }
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (bytes, rest) = self.buf.split_first_chunk::<N>()?;
+        self.buf = rest;
+        Some(*bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes()?))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes()?))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes()?))
+    }
+
+    pub fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.bytes()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writer_rejects_record_that_doesnt_fit() {
+        let mut buf = [0u8; 6];
+        let mut w = Writer::new(&mut buf);
+        w.u32(0x0403_0201);
+        w.u16(0x0605);
+        assert_eq!(w.data().unwrap(), &[1, 2, 3, 4, 5, 6]);
+        w.u8(7);
+        assert!(matches!(w.data(), Err(StorageError::TooLarge)));
+    }
+}
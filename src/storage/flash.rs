@@ -0,0 +1,111 @@
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, PARTITION_TABLE_MAX_LEN, PartitionType,
+};
+use esp_rom_sys::rom::crc::crc32_le;
+use esp_storage::{FlashStorage, FlashStorageError};
+
+use super::{HEADER_SIZE, MAX_RECORD_SIZE, SECTOR_SIZE, Slot, StorageError};
+
+const MAGIC: u32 = 0x5A42_5354;
+
+impl From<FlashStorageError> for StorageError {
+    fn from(e: FlashStorageError) -> Self {
+        match e {
+            FlashStorageError::OutOfBounds => StorageError::OutOfBounds,
+            e => StorageError::Flash(e),
+        }
+    }
+}
+
+// Versioned, CRC-checked records in the NVS data partition.
+// Nothing else on the robot uses NVS, so the partition is used as raw storage.
+// Interrupts are disabled while the flash is busy, so records should only be saved
+// while the motors are stopped
+pub struct Store {
+    flash: FlashStorage<'static>,
+    offset: u32,
+    size: u32,
+    // Header and data of the record being saved, so its sector is only written once
+    record: [u8; SECTOR_SIZE],
+}
+
+impl Store {
+    pub fn new(mut flash: FlashStorage<'static>) -> Result<Self, StorageError> {
+        let mut table = [0u8; PARTITION_TABLE_MAX_LEN];
+        let table = partitions::read_partition_table(&mut flash, &mut table)
+            .map_err(|_| StorageError::NoPartition)?;
+        let partition = table
+            .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))
+            .ok()
+            .flatten()
+            .ok_or(StorageError::NoPartition)?;
+        let (offset, size) = (partition.offset(), partition.len());
+        log::info!("Storage partition at {:#x}, {} bytes", offset, size);
+        Ok(Self {
+            flash,
+            offset,
+            size,
+            record: [0; SECTOR_SIZE],
+        })
+    }
+
+    // Slots outside the partition are rejected, the flash itself is never accessed beyond it
+    fn address(&self, slot: Slot) -> Result<u32, StorageError> {
+        let start = slot as u32 * SECTOR_SIZE as u32;
+        if start + SECTOR_SIZE as u32 > self.size {
+            return Err(StorageError::OutOfBounds);
+        }
+        Ok(self.offset + start)
+    }
+
+    // CRC covers the version too, so a record is never read back as another version
+    fn crc(version: u16, data: &[u8]) -> u32 {
+        !crc32_le(crc32_le(!0, &version.to_le_bytes()), data)
+    }
+
+    // Reads a record into `buf`. Returns its data
+    pub fn load<'b>(
+        &mut self,
+        slot: Slot,
+        version: u16,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], StorageError> {
+        let address = self.address(slot)?;
+        let mut header = [0u8; HEADER_SIZE];
+        self.flash.read(address, &mut header)?;
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let saved_version = u16::from_le_bytes(header[4..6].try_into().unwrap());
+        let len = u16::from_le_bytes(header[6..8].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if magic != MAGIC || saved_version != version {
+            return Err(StorageError::NotFound);
+        }
+        if len > buf.len() || len > MAX_RECORD_SIZE {
+            return Err(StorageError::Corrupted);
+        }
+        let data = &mut buf[..len];
+        self.flash.read(address + HEADER_SIZE as u32, data)?;
+        if Self::crc(version, data) != crc {
+            return Err(StorageError::Corrupted);
+        }
+        Ok(data)
+    }
+
+    pub fn save(&mut self, slot: Slot, version: u16, data: &[u8]) -> Result<(), StorageError> {
+        if data.len() > MAX_RECORD_SIZE {
+            return Err(StorageError::TooLarge);
+        }
+        let address = self.address(slot)?;
+        let record = &mut self.record[..HEADER_SIZE + data.len()];
+        record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4..6].copy_from_slice(&version.to_le_bytes());
+        record[6..8].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        record[8..12].copy_from_slice(&Self::crc(version, data).to_le_bytes());
+        record[HEADER_SIZE..].copy_from_slice(data);
+        // Record fits a sector, so it is erased and written once.
+        // A power loss in between leaves a record with a bad CRC, which loads as Corrupted
+        self.flash.write(address, record)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use heapless::Vec;
+
+use super::{MAX_RECORD_SIZE, Slot, StorageError};
+
+const SLOTS: usize = 5;
+
+type Record = (u16, Vec<u8, MAX_RECORD_SIZE>);
+
+// Same records as the flash Store, kept in RAM for host builds
+pub struct Store {
+    records: [Option<Record>; SLOTS],
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Self {
+            records: core::array::from_fn(|_| None),
+        }
+    }
+
+    pub fn load<'b>(
+        &mut self,
+        slot: Slot,
+        version: u16,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], StorageError> {
+        let (saved_version, data) = self.records[slot as usize]
+            .as_ref()
+            .ok_or(StorageError::NotFound)?;
+        if *saved_version != version {
+            return Err(StorageError::NotFound);
+        }
+        let buf = buf.get_mut(..data.len()).ok_or(StorageError::Corrupted)?;
+        buf.copy_from_slice(data);
+        Ok(buf)
+    }
+
+    pub fn save(&mut self, slot: Slot, version: u16, data: &[u8]) -> Result<(), StorageError> {
+        let data = Vec::from_slice(data).map_err(|_| StorageError::TooLarge)?;
+        self.records[slot as usize] = Some((version, data));
+        Ok(())
+    }
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new()
+    }
+}
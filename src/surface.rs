@@ -0,0 +1,68 @@
+use crate::color::Color;
+use crate::storage::{Slot, StorageError, Store};
+
+const SURFACE_VERSION: u16 = 1;
+
+// Surfaces the robot drives on. Each has its own motor config, tuned and learned separately
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Surface {
+    Table,
+    Carpet,
+    Foam,
+}
+
+impl Surface {
+    pub fn name(self) -> &'static str {
+        match self {
+            Surface::Table => "table",
+            Surface::Carpet => "carpet",
+            Surface::Foam => "foam",
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Surface::Table),
+            1 => Some(Surface::Carpet),
+            2 => Some(Surface::Foam),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    // Calibration cards shown to the robot while it is blocked
+    pub fn from_card(color: Color) -> Option<Self> {
+        match color {
+            Color::Green => Some(Surface::Table),
+            Color::Cyan => Some(Surface::Carpet),
+            Color::Yellow => Some(Surface::Foam),
+            _ => None,
+        }
+    }
+
+    // Where the motor config for the surface is stored
+    pub fn slot(self) -> Slot {
+        match self {
+            Surface::Table => Slot::TableConfig,
+            Surface::Carpet => Slot::CarpetConfig,
+            Surface::Foam => Slot::FoamConfig,
+        }
+    }
+
+    // Surface selected before the last power-off
+    pub fn load(store: &mut Store) -> Result<Self, StorageError> {
+        let mut buf = [0u8; 1];
+        let data = store.load(Slot::Surface, SURFACE_VERSION, &mut buf)?;
+        data.first()
+            .copied()
+            .and_then(Self::from_u8)
+            .ok_or(StorageError::Corrupted)
+    }
+
+    pub fn save(self, store: &mut Store) -> Result<(), StorageError> {
+        store.save(Slot::Surface, SURFACE_VERSION, &[self.to_u8()])
+    }
+}
@@ -1,6 +1,20 @@
+use crate::program::{TAPE_SIZE, Tape};
+
 pub const MAGIC: u32 = 0xDEAD_BEEF;
-pub const REVISION: u32 = 1;
-pub const PACKET_SIZE: usize = 20;
+pub const REVISION: u32 = 6;
+pub const PACKET_SIZE: usize = 34;
+
+// Program packets have their own magic, so receivers can tell them apart
+pub const PROGRAM_MAGIC: u32 = 0xDEAD_C0DE;
+pub const PROGRAM_REVISION: u32 = 1;
+// magic, revision, length, colors
+pub const PROGRAM_PACKET_SIZE: usize = 9 + TAPE_SIZE;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Telemetry {
+    Status(TelemetryPacket),
+    Program(ProgramPacket),
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct TelemetryPacket {
@@ -9,6 +23,19 @@ pub struct TelemetryPacket {
     pub right_duty: u8,
     pub left_pulses: u32,
     pub right_pulses: u32,
+    // Battery compensation of the motor duty, in 1/1000
+    pub compensation: u16,
+    // Wheel speed controller gains, scaled by 256. Auto-tune results show up here
+    pub kp: i16,
+    pub ki: i16,
+    pub kd: i16,
+    // Wheel slip and drag detected since power-up, wrapping around
+    pub slips: u16,
+    pub drags: u16,
+    // Surface whose motor config is in use
+    pub surface: u8,
+    // Motor driver failed, the motors stay off until a ClearFault remote command
+    pub faulted: bool,
 }
 
 #[allow(dead_code)]
@@ -21,6 +48,14 @@ pub fn pack(pkt: &TelemetryPacket) -> [u8; PACKET_SIZE] {
     buf[11] = pkt.right_duty;
     buf[12..16].copy_from_slice(&pkt.left_pulses.to_le_bytes());
     buf[16..20].copy_from_slice(&pkt.right_pulses.to_le_bytes());
+    buf[20..22].copy_from_slice(&pkt.compensation.to_le_bytes());
+    buf[22..24].copy_from_slice(&pkt.kp.to_le_bytes());
+    buf[24..26].copy_from_slice(&pkt.ki.to_le_bytes());
+    buf[26..28].copy_from_slice(&pkt.kd.to_le_bytes());
+    buf[28..30].copy_from_slice(&pkt.slips.to_le_bytes());
+    buf[30..32].copy_from_slice(&pkt.drags.to_le_bytes());
+    buf[32] = pkt.surface;
+    buf[33] = pkt.faulted as u8;
     buf
 }
 
@@ -43,5 +78,70 @@ pub fn unpack(buf: &[u8]) -> Option<TelemetryPacket> {
         right_duty: buf[11],
         left_pulses: u32::from_le_bytes(buf[12..16].try_into().ok()?),
         right_pulses: u32::from_le_bytes(buf[16..20].try_into().ok()?),
+        compensation: u16::from_le_bytes(buf[20..22].try_into().ok()?),
+        kp: i16::from_le_bytes(buf[22..24].try_into().ok()?),
+        ki: i16::from_le_bytes(buf[24..26].try_into().ok()?),
+        kd: i16::from_le_bytes(buf[26..28].try_into().ok()?),
+        slips: u16::from_le_bytes(buf[28..30].try_into().ok()?),
+        drags: u16::from_le_bytes(buf[30..32].try_into().ok()?),
+        surface: buf[32],
+        faulted: buf[33] != 0,
+    })
+}
+
+// Recorded mats, as Color::to_u8() values
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramPacket {
+    pub len: u8,
+    pub colors: [u8; TAPE_SIZE],
+}
+
+impl ProgramPacket {
+    pub fn new(tape: &Tape) -> Self {
+        let mut colors = [0u8; TAPE_SIZE];
+        for (b, c) in colors.iter_mut().zip(tape.colors()) {
+            *b = c.to_u8();
+        }
+        Self {
+            len: tape.len() as u8,
+            colors,
+        }
+    }
+
+    pub fn colors(&self) -> &[u8] {
+        &self.colors[..self.len as usize]
+    }
+}
+
+#[allow(dead_code)]
+pub fn pack_program(pkt: &ProgramPacket) -> [u8; PROGRAM_PACKET_SIZE] {
+    let mut buf = [0u8; PROGRAM_PACKET_SIZE];
+    buf[0..4].copy_from_slice(&PROGRAM_MAGIC.to_le_bytes());
+    buf[4..8].copy_from_slice(&PROGRAM_REVISION.to_le_bytes());
+    buf[8] = pkt.len;
+    buf[9..].copy_from_slice(&pkt.colors);
+    buf
+}
+
+#[allow(dead_code)]
+pub fn unpack_program(buf: &[u8]) -> Option<ProgramPacket> {
+    if buf.len() < PROGRAM_PACKET_SIZE {
+        return None;
+    }
+    let magic = u32::from_le_bytes(buf[0..4].try_into().ok()?);
+    if magic != PROGRAM_MAGIC {
+        return None;
+    }
+    let revision = u32::from_le_bytes(buf[4..8].try_into().ok()?);
+    if revision != PROGRAM_REVISION {
+        return None;
+    }
+    let len = buf[8];
+    if len as usize > TAPE_SIZE {
+        return None;
+    }
+    Some(ProgramPacket {
+        len,
+        colors: buf[9..PROGRAM_PACKET_SIZE].try_into().ok()?,
     })
 }